@@ -212,7 +212,7 @@ impl Node {
             if message == self.termination_message {
                 return false;
             }
-            unwrap!(self.gossiper.send_new(&message));
+            let _ = unwrap!(self.gossiper.send_new(&message));
         }
         true
     }
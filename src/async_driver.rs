@@ -0,0 +1,217 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use error::Error;
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use gossip::Gossip;
+use hasher::{Hasher, Sha3Hasher};
+use messages::GossipRpc;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+/// Signals one or more `RoundDriver`s to stop.  Clones share the same state, so any clone may be
+/// used to cancel.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<TokenInner>,
+}
+
+#[derive(Default)]
+struct TokenInner {
+    cancelled: AtomicBool,
+    // The tasks to wake on cancellation.
+    tasks: Mutex<Vec<Task>>,
+}
+
+impl CancellationToken {
+    /// Construct a new token which hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the token, causing any drivers using it to complete before their next round.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        for task in lock(&self.inner.tasks).drain(..) {
+            task.notify();
+        }
+    }
+
+    /// Returns whether `cancel()` has been called on this token or any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Arranges for the current task to be woken on cancellation.
+    fn register(&self) {
+        let mut tasks = lock(&self.inner.tasks);
+        if !tasks.iter().any(Task::will_notify_current) {
+            tasks.push(task::current());
+        }
+    }
+}
+
+/// A future which ends a round of a shared `Gossip` on a fixed interval, passing each round's Push
+/// RPCs to a send closure.  As with `Gossip::next_round()`, these are all to be sent to a single
+/// random peer, and a single empty Push is passed if there is nothing to push.
+///
+/// The future completes once its `CancellationToken` is cancelled, and must be run on a Tokio
+/// runtime with a timer.  It fails with `Error::Poisoned` if another holder of the lock panicked,
+/// since the `Gossip` may then have been left part-way through an update.
+pub struct RoundDriver<F, H: Hasher = Sha3Hasher> {
+    gossip: Arc<Mutex<Gossip<H>>>,
+    interval: Interval,
+    token: CancellationToken,
+    send: F,
+}
+
+impl<F, H> RoundDriver<F, H>
+where
+    F: FnMut(Vec<GossipRpc>),
+    H: Hasher,
+{
+    /// Construct a driver for `gossip` whose first round ends one `interval` from now.  The lock
+    /// is only held while the round is ended, not while `send` is called.
+    pub fn new(
+        gossip: Arc<Mutex<Gossip<H>>>,
+        interval: Duration,
+        token: CancellationToken,
+        send: F,
+    ) -> Self {
+        RoundDriver {
+            gossip,
+            interval: Interval::new(Instant::now() + interval, interval),
+            token,
+            send,
+        }
+    }
+}
+
+impl<F, H> Future for RoundDriver<F, H>
+where
+    F: FnMut(Vec<GossipRpc>),
+    H: Hasher,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        // Register before checking, so that a cancellation in between isn't missed.
+        self.token.register();
+        loop {
+            if self.token.is_cancelled() {
+                return Ok(Async::Ready(()));
+            }
+            let tick = self.interval.poll().map_err(|error| {
+                io::Error::new(io::ErrorKind::Other, error)
+            })?;
+            match tick {
+                Async::Ready(Some(_)) => {
+                    let push_list = self.gossip
+                        .lock()
+                        .map_err(|_| Error::Poisoned)?
+                        .next_round();
+                    (self.send)(push_list);
+                }
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+// Only used for the token's task list, which is only ever pushed to or drained while locked.
+// Neither can leave it inconsistent, so poisoning is ignored.
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn drive_rounds_until_cancelled() {
+        let mut gossip: Gossip = Gossip::new();
+        gossip.set_total_peers(20);
+        let _ = unwrap!(gossip.new_message(b"message".to_vec()));
+        let gossip = Arc::new(Mutex::new(gossip));
+
+        let token = CancellationToken::new();
+        let (sender, receiver) = mpsc::channel();
+        let canceller = token.clone();
+        let send = move |push_list: Vec<GossipRpc>| {
+            // Rounds may still be driven after the watcher has stopped listening.
+            let _ = sender.send(push_list);
+        };
+        let driver = RoundDriver::new(gossip.clone(), Duration::from_millis(10), token, send);
+
+        // Cancel from another thread once three rounds have been driven.
+        let watcher = thread::spawn(move || {
+            let push_lists: Vec<_> = receiver.iter().take(3).collect();
+            canceller.cancel();
+            push_lists
+        });
+        let mut runtime = unwrap!(Runtime::new());
+        unwrap!(runtime.block_on(driver));
+
+        let push_lists = unwrap!(watcher.join());
+        assert_eq!(push_lists.len(), 3);
+        assert!(push_lists.iter().all(|push_list| push_list.len() == 1));
+        assert!(unwrap!(gossip.lock()).current_round() >= 3);
+    }
+
+    #[test]
+    fn cancelled_before_start() {
+        let gossip: Arc<Mutex<Gossip>> = Arc::new(Mutex::new(Gossip::new()));
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut rounds = 0;
+        {
+            let driver =
+                RoundDriver::new(gossip.clone(), Duration::from_millis(1), token, |_| rounds += 1);
+            let mut runtime = unwrap!(Runtime::new());
+            unwrap!(runtime.block_on(driver));
+        }
+        assert_eq!(rounds, 0);
+        assert_eq!(unwrap!(gossip.lock()).current_round(), 0);
+    }
+
+    #[test]
+    fn poisoned_gossip() {
+        let gossip: Arc<Mutex<Gossip>> = Arc::new(Mutex::new(Gossip::new()));
+        let poisoner = gossip.clone();
+        let _ = thread::spawn(move || {
+            let _guard = unwrap!(poisoner.lock());
+            panic!("Poisoning the lock");
+        }).join();
+        let token = CancellationToken::new();
+        let driver = RoundDriver::new(gossip, Duration::from_millis(1), token, |_| ());
+        let mut runtime = unwrap!(Runtime::new());
+        match runtime.block_on(driver) {
+            Err(Error::Poisoned) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
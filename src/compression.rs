@@ -0,0 +1,50 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use error::Error;
+#[cfg(feature = "compression")]
+use flate2::Compression;
+#[cfg(feature = "compression")]
+use flate2::read::DeflateDecoder;
+#[cfg(feature = "compression")]
+use flate2::write::DeflateEncoder;
+#[cfg(feature = "compression")]
+use std::io::{Read, Write};
+
+#[cfg(feature = "compression")]
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(feature = "compression")]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut decompressed = Vec::new();
+    let _ = DeflateDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn compress(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::CompressionUnsupported)
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_data: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::CompressionUnsupported)
+}
@@ -31,7 +31,47 @@ quick_error! {
             description("Already started gossiping")
             display("Connections to all other nodes must be made before sending any messages.")
         }
-        /// Failed in verify signature.
+        /// Message is empty.
+        Empty {
+            description("Message is empty")
+            display("Empty messages cannot be gossiped.")
+        }
+        /// Message exceeds the maximum permitted size.
+        TooLarge {
+            description("Message too large")
+            display("The message exceeds the maximum permitted size.")
+        }
+        /// Encoded RPCs are malformed.
+        Malformed {
+            description("Malformed encoding")
+            display("The encoded RPCs are truncated or otherwise malformed.")
+        }
+        /// No message with the given hash is held.
+        UnknownDigest {
+            description("Unknown message")
+            display("No message with the given hash is held.")
+        }
+        /// A different message with the same hash is already held.
+        DigestCollision {
+            description("Hash collision")
+            display("A different message with the same hash is already held.")
+        }
+        /// A message is held compressed but compression support isn't enabled.
+        CompressionUnsupported {
+            description("Compression unsupported")
+            display("This build lacks the \"compression\" feature needed to decompress messages.")
+        }
+        /// A peer sent a message for a new topic, but the maximum number of topics are held.
+        TooManyTopics {
+            description("Too many topics")
+            display("The maximum number of topics are already held.")
+        }
+        /// A lock on shared state was poisoned by a panic while it was held.
+        Poisoned {
+            description("Lock poisoned")
+            display("A panic while holding a lock may have left the shared state inconsistent.")
+        }
+        /// Failed in verify signature, either of an RPC or, in signed mode, of a message.
         SigFailure {
             description("Signature cannot be verified")
             display("The message or signature might be corrupted, or the signer is wrong.")
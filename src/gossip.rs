@@ -15,18 +15,41 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use compression;
+use error::Error;
+use hasher::{Digest256, Hasher, Sha3Hasher, hash_message};
 use id::Id;
-use message_state::MessageState;
-use messages::GossipRpc;
-use std::{cmp, mem, u64};
-use std::collections::{BTreeMap, BTreeSet};
-use std::collections::btree_map::Entry;
+use message_state::{MedianRule, MessageState, Phase, RoundParams};
+use messages::{GossipRpc, RpcKind};
+#[cfg(feature = "signed")]
+use signed;
+use std::{cmp, mem, u64, u8, usize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
 
-/// Gossip protocol handler.
-pub struct Gossip {
-    messages: BTreeMap<Vec<u8>, MessageState>,
+/// The default maximum size in bytes of a message which can be gossiped.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+/// The maximum number of undrained events buffered.  Once reached, the oldest are discarded.
+pub const MAX_EVENTS: usize = 1024;
+/// The maximum number of peers counted.  Networks any larger can't be counted exactly, so further
+/// peers are ignored.  As the thresholds grow only logarithmically, this makes no practical
+/// difference: they're around 37 rounds in total and 4 in state C by then.
+pub const MAX_TOTAL_PEERS: u64 = (1 << 53) - 1;
+
+/// Gossip protocol handler, identifying messages by their hash under `H`.  The entire state can be
+/// captured via `snapshot()`, allowing a restored instance to resume gossiping exactly where it
+/// left off.
+pub struct Gossip<H: Hasher = Sha3Hasher> {
+    // The held messages, keyed by hash.  A `BTreeMap` allocates a node at a time as it grows, so
+    // unlike a `HashMap` there's no capacity to reserve up front, and no rehashing to avoid.  Its
+    // iteration in hash order is also relied upon, so that push and pull lists, eviction and
+    // snapshots are deterministic across nodes and runs.
+    messages: BTreeMap<H::Digest, StoredMessage>,
     network_size: f64,
+    // The peers registered via `add_peer_id()`.  Empty if only the count-only API is used.
+    peer_ids: BTreeSet<Id>,
     // When in state B, if our counter for a message is incremented to this value, the state
     // transitions to C.  Specified in the paper as `O(ln ln n)`.
     counter_max: u8,
@@ -37,111 +60,1049 @@ pub struct Gossip {
     // failsafe to allow the definite termination of a message being propagated.  Specified in the
     // paper as `O(ln n)`.
     max_rounds: u8,
+    // The tunable settings.  The three thresholds above are `config.rounds_override` if set, and
+    // are otherwise derived from the network size and `config.min_rounds`.
+    config: GossipConfig,
+    // The hashes of pruned terminated messages, mapped to the round in which each was pruned.
+    tombstones: BTreeMap<H::Digest, u64>,
     // All peers with which we communicated during this round.
     peers_in_this_round: BTreeSet<Id>,
+    // The weights given to peers' counters during this round, for those other than `1`.
+    peer_weights: BTreeMap<Id, u32>,
     // Statistics
     statistics: Statistics,
+    // If enabled, the number of times a message in state B ended a round having been received from
+    // each number of peers, indexed by that number.  Empty unless enabled.
+    hit_histogram: Vec<u32>,
+    // Events not yet drained.  These are transient notifications, so aren't persisted.
+    events: VecDeque<GossipEvent<H::Digest>>,
+    // The messages whose counters were incremented by the median rule at the end of the last
+    // round.  Like the events, this is transient so isn't persisted.
+    median_bumps: Vec<H::Digest>,
+    // The messages held back by the push budget at the end of the last round.  Also transient.
+    skipped: Vec<H::Digest>,
+    _hasher: PhantomData<H>,
 }
 
-impl Gossip {
+impl<H: Hasher> Gossip<H> {
+    /// The default minimum `counter_max`, i.e. the default minimum counter value at which a
+    /// message moves from state B to state C, derived as `max(MIN_HOT_ROUNDS, ceil(ln ln n))` for
+    /// a network of `n` nodes.  See `set_min_rounds()`.
+    pub const MIN_HOT_ROUNDS: u8 = 1;
+    /// The default minimum `max_c_rounds`, derived as `max(MIN_COLD_ROUNDS, ceil(ln ln n))`.
+    pub const MIN_COLD_ROUNDS: u8 = 1;
+    /// The default minimum `max_rounds`, derived as `max(MIN_TOTAL_ROUNDS, ceil(ln n))`.
+    pub const MIN_TOTAL_ROUNDS: u8 = 1;
+
+    /// Construct a new `Gossip` for a network comprising just this node.
     pub fn new() -> Self {
-        Gossip {
+        Self::with_config(GossipConfig::default())
+    }
+
+    // Constructs a new `Gossip` for a network comprising just this node with the given settings,
+    // raising any thresholds given as `0` to `1` as per `set_rounds()` and `set_min_rounds()`.
+    fn with_config(mut config: GossipConfig) -> Self {
+        config.min_rounds = at_least_one(config.min_rounds);
+        config.rounds_override = config.rounds_override.map(at_least_one);
+        let mut gossip = Gossip {
             messages: BTreeMap::new(),
             network_size: 1.0,
+            peer_ids: BTreeSet::new(),
             counter_max: 0,
             max_c_rounds: 0,
             max_rounds: 0,
+            config,
+            tombstones: BTreeMap::new(),
             peers_in_this_round: BTreeSet::new(),
+            peer_weights: BTreeMap::new(),
             statistics: Statistics::default(),
+            hit_histogram: Vec::new(),
+            events: VecDeque::new(),
+            median_bumps: Vec::new(),
+            skipped: Vec::new(),
+            _hasher: PhantomData,
+        };
+        gossip.update_rounds();
+        gossip
+    }
+
+    /// Construct a new `Gossip` for a network comprising this node and `total_peers` peers.  This
+    /// is equivalent to calling `add_peer()` `total_peers` times on a new instance.
+    pub fn with_peers(total_peers: u64) -> Self {
+        let mut gossip = Self::new();
+        gossip.set_total_peers(total_peers);
+        gossip
+    }
+
+    /// Construct a new `Gossip` for a network comprising just this node, already holding each of
+    /// `items` as if just started by this node with the given counter (or `1` if given as `0`),
+    /// e.g. to bootstrap from a replayed log.  The network size can then be set as usual via
+    /// `set_total_peers()`; any message whose counter has reached `counter_max()` by the end of the
+    /// first round moves to state C.  As with `new_messages()`, duplicates are harmless.
+    ///
+    /// Any message which `new_message()` would reject, i.e. one which is empty, larger than
+    /// `DEFAULT_MAX_MESSAGE_SIZE` or whose hash collides with an earlier item's, is dropped.  Use
+    /// `try_from_messages()` to be told of these instead.
+    pub fn from_messages(items: Vec<(u8, Vec<u8>)>) -> Self {
+        let mut gossip = Self::new();
+        for (counter, msg) in items {
+            if let Err(error) = gossip.preload_message(counter, msg) {
+                debug!("Dropping a message to preload: {}", error);
+            }
+        }
+        gossip
+    }
+
+    /// Construct a new `Gossip` holding each of `items` as per `from_messages()`, but failing with
+    /// the corresponding error if any message would be dropped.
+    pub fn try_from_messages(items: Vec<(u8, Vec<u8>)>) -> Result<Self, Error> {
+        let mut gossip = Self::new();
+        for (counter, msg) in items {
+            gossip.preload_message(counter, msg)?;
         }
+        Ok(gossip)
     }
 
+    /// Increase the network size by one, unless there are already `MAX_TOTAL_PEERS` peers.
     pub fn add_peer(&mut self) {
-        self.network_size += 1.0;
-        self.counter_max = cmp::max(1, self.network_size.ln().ln().ceil() as u8);
-        self.max_c_rounds = cmp::max(1, self.network_size.ln().ln().ceil() as u8);
-        self.max_rounds = cmp::max(1, self.network_size.ln().ceil() as u8);
+        self.network_size = (self.network_size + 1.0).min(MAX_TOTAL_PEERS as f64 + 1.0);
+        self.update_rounds();
     }
 
-    pub fn messages(&self) -> Vec<Vec<u8>> {
+    /// Set the number of peers in the network (excluding this node) in a single step.  This
+    /// replaces rather than adds to the current count, and is equivalent to calling `add_peer()`
+    /// `total_peers` times on a new instance, so is clamped to `MAX_TOTAL_PEERS`.
+    ///
+    /// Unless overridden via `set_rounds()`, the thresholds are derived from the network size
+    /// alone, and never decrease as it grows: a larger network never shortens propagation.
+    pub fn set_total_peers(&mut self, total_peers: u64) {
+        self.network_size = cmp::min(total_peers, MAX_TOTAL_PEERS) as f64 + 1.0;
+        self.update_rounds();
+    }
+
+    /// Register `peer_id` as part of the network, setting the number of peers to the number
+    /// registered.  Returns `false` if it was already registered.  This replaces any count set via
+    /// the count-only methods such as `add_peer()`, so the two styles shouldn't be mixed.
+    pub fn add_peer_id(&mut self, peer_id: Id) -> bool {
+        let added = self.peer_ids.insert(peer_id);
+        let total_peers = self.peer_ids.len() as u64;
+        self.set_total_peers(total_peers);
+        added
+    }
+
+    /// Deregister `peer_id`, setting the number of peers to the number still registered.  Returns
+    /// `false` if it wasn't registered.
+    pub fn remove_peer_id(&mut self, peer_id: &Id) -> bool {
+        let removed = self.peer_ids.remove(peer_id);
+        let total_peers = self.peer_ids.len() as u64;
+        self.set_total_peers(total_peers);
+        removed
+    }
+
+    /// Returns the peers registered via `add_peer_id()`, in ascending order.
+    pub fn peer_ids(&self) -> impl Iterator<Item = &Id> {
+        self.peer_ids.iter()
+    }
+
+    /// Set the maximum size in bytes of a message which will be accepted, either from this node or
+    /// from peers.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.config.max_message_size = max_message_size;
+    }
+
+    /// Set the maximum number of peer counters recorded per message per round.  Only whether each
+    /// counter is less than or not less than ours matters when deciding to increment our counter,
+    /// so this just bounds the evidence considered.  `None`, the default, records every peer's
+    /// counter.  Counters ignored once the cap is reached are tallied in
+    /// `Statistics::hits_dropped`.
+    pub fn set_max_hits_per_round(&mut self, max_hits_per_round: Option<usize>) {
+        self.config.max_hits_per_round = max_hits_per_round;
+    }
+
+    /// Set the maximum number of messages pushed per round, or `None` to push every eligible one.
+    ///
+    /// When the budget bites, the messages with the lowest counters are pushed, ties being broken
+    /// in favour of the fewest rounds elapsed and then by hash.  Only the pushed messages have
+    /// their state advanced by the round; the rest are held back unchanged (retaining any counters
+    /// already received from peers this round).  Since held-back messages then have fewer rounds
+    /// elapsed than pushed ones with the same counter, they take priority in subsequent rounds, so
+    /// none are starved indefinitely.
+    pub fn set_max_push_per_round(&mut self, max_push_per_round: Option<usize>) {
+        self.config.max_push_per_round = max_push_per_round;
+    }
+
+    /// Set the maximum number of rounds a message is held for before `prune_expired()` evicts it,
+    /// whether or not its propagation has completed.  `None` disables expiry.
+    pub fn set_max_age_rounds(&mut self, max_age_rounds: Option<u64>) {
+        self.config.max_age_rounds = max_age_rounds;
+    }
+
+    /// Set the number of rounds for which the hash of each message removed by `prune_terminated()`
+    /// is kept as a tombstone, or `None` to keep none.  While a hash is tombstoned, further copies
+    /// of that message received from peers are ignored rather than being treated as new.  Disabling
+    /// tombstones discards any already held.
+    pub fn set_tombstone_rounds(&mut self, tombstone_rounds: Option<u64>) {
+        self.config.tombstone_rounds = tombstone_rounds;
+        if tombstone_rounds.is_none() {
+            self.tombstones.clear();
+        }
+    }
+
+    /// Returns whether the message with the given hash has been pruned and is still tombstoned.
+    pub fn is_tombstoned(&self, digest: &H::Digest) -> bool {
+        self.tombstones.contains_key(digest)
+    }
+
+    /// Set the maximum total size in bytes of all held messages, or `None` for no limit.  Whenever
+    /// a new message takes the total over the limit, other messages are evicted until it's back
+    /// within it: first those in state D, then those in state C, then those in state B, in each
+    /// case those with the most rounds elapsed first and ties broken by hash.  The new message
+    /// itself is never evicted, so it may exceed the limit alone.
+    pub fn set_max_total_bytes(&mut self, max_total_bytes: Option<usize>) {
+        self.config.max_total_bytes = max_total_bytes;
+    }
+
+    /// Set the value to which counters received from peers are clamped before being used, or
+    /// `None` (the default) to use them as received.
+    ///
+    /// Without a cap, a single peer reporting a counter of at least `counter_max()` moves a message
+    /// straight to state C, so a malicious peer can cut short its exponential-growth phase and
+    /// hence its spread.  A cap below `counter_max()` prevents that, but then honest peers in state
+    /// C can't hasten the transition either, so messages only leave state B via the median rule or
+    /// by reaching `max_rounds()`, costing more rounds and bandwidth than necessary.
+    pub fn set_trusted_counter_cap(&mut self, trusted_counter_cap: Option<u8>) {
+        self.config.trusted_counter_cap = trusted_counter_cap;
+    }
+
+    /// Set the rule by which peers' counters for a message in state B advance ours at the end of
+    /// each round.  The default is `MedianRule::Majority`, as per the paper.
+    pub fn set_median_rule(&mut self, median_rule: MedianRule) {
+        self.config.median_rule = median_rule;
+    }
+
+    /// Set the number of times each message may be returned to state B by `refresh()` once it has
+    /// left it, or `None` (the default) for no limit.  Once the limit is reached, refreshing the
+    /// message terminates it instead, bounding how often it can be re-propagated.
+    pub fn set_max_rehot(&mut self, max_rehot: Option<u8>) {
+        self.config.max_rehot = max_rehot;
+    }
+
+    /// Set whether messages subsequently stored are held compressed, trading CPU time for memory.
+    /// Messages are always hashed, sent and returned uncompressed, so this needn't match other
+    /// nodes' settings.
+    #[cfg(feature = "compression")]
+    pub fn set_compress_payloads(&mut self, compress_payloads: bool) {
+        self.config.compress_payloads = compress_payloads;
+    }
+
+    /// Set whether this instance is in signed mode, where every message, whether new from this
+    /// node or received from a peer, must have been signed via `new_signed_message()`.  Any other
+    /// is rejected with `Error::SigFailure` without affecting our state.  All nodes on the network
+    /// should use the same mode.  Messages already held aren't checked.
+    #[cfg(feature = "signed")]
+    pub fn set_signed(&mut self, signed: bool) {
+        self.config.signed = signed;
+    }
+
+    /// Decrease the network size by one, never dropping below this node alone.
+    pub fn remove_peer(&mut self) {
+        self.network_size = (self.network_size - 1.0).max(1.0);
+        self.update_rounds();
+    }
+
+    /// Override the thresholds otherwise derived from the network size, each being raised to `1`
+    /// if given as `0`.  Until `clear_rounds_override()` is called, subsequent changes to the
+    /// network size no longer recompute them.
+    pub fn set_rounds(&mut self, counter_max: u8, max_c_rounds: u8, max_rounds: u8) {
+        self.config.rounds_override = Some(at_least_one((counter_max, max_c_rounds, max_rounds)));
+        self.update_rounds();
+    }
+
+    /// Set the minimum values of the thresholds derived from the network size, each being raised to
+    /// `1` if given as `0`.  The defaults (`MIN_HOT_ROUNDS`, etc.) are all `1`, which for small
+    /// networks yields thresholds too low to propagate messages reliably; raising them trades extra
+    /// rounds for robustness, while larger networks are unaffected where the derived thresholds
+    /// already exceed the minimums.
+    pub fn set_min_rounds(&mut self, counter_max: u8, max_c_rounds: u8, max_rounds: u8) {
+        self.config.min_rounds = at_least_one((counter_max, max_c_rounds, max_rounds));
+        self.update_rounds();
+    }
+
+    /// Discard any thresholds set via `set_rounds()`, recomputing them from the network size now
+    /// and whenever it subsequently changes.
+    pub fn clear_rounds_override(&mut self) {
+        self.config.rounds_override = None;
+        self.update_rounds();
+    }
+
+    /// The counter value at which a message transitions from state B to state C.
+    pub fn counter_max(&self) -> u8 {
+        self.counter_max
+    }
+
+    /// The maximum number of rounds a message remains in state C.
+    pub fn max_c_rounds(&self) -> u8 {
+        self.max_c_rounds
+    }
+
+    /// The maximum total number of rounds a message remains in states B or C.
+    pub fn max_rounds(&self) -> u8 {
+        self.max_rounds
+    }
+
+    /// The total size in bytes of all held messages, as stored (i.e. compressed if applicable).
+    pub fn total_bytes(&self) -> usize {
+        self.messages.values().map(|stored| stored.msg.len()).sum()
+    }
+
+    /// The fraction of the capacity set via `set_max_total_bytes()` currently used, from `0.0` to
+    /// `1.0`.  This is `0.0` if no limit is set.  It's an advisory signal, e.g. for a producer of
+    /// new messages to slow down as it approaches `1.0`, at which point older messages start being
+    /// evicted.
+    pub fn pressure(&self) -> f32 {
+        match self.config.max_total_bytes {
+            None => 0.0,
+            Some(0) if self.messages.is_empty() => 0.0,
+            Some(0) => 1.0,
+            Some(max_total_bytes) => {
+                (self.total_bytes() as f64 / max_total_bytes as f64).min(1.0) as f32
+            }
+        }
+    }
+
+    /// The number of messages currently held, in any state.
+    pub fn message_count(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Returns `true` if no messages are held.
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Returns `true` if the message with the given hash is held.
+    pub fn has_message(&self, digest: &H::Digest) -> bool {
+        self.messages.contains_key(digest)
+    }
+
+    /// Returns `true` if the given message is held.
+    pub fn has_message_bytes(&self, msg: &[u8]) -> bool {
+        self.has_message(&H::hash(msg))
+    }
+
+    /// Returns the message with the given hash along with our state for it, or
+    /// `Error::UnknownDigest` if it isn't held.
+    pub fn message(&self, digest: &H::Digest) -> Result<&StoredMessage, Error> {
+        self.messages.get(digest).ok_or(Error::UnknownDigest)
+    }
+
+    /// Returns a borrowed view of the message with the given hash, or `None` if it isn't held.
+    pub fn message_ref<'a>(&'a self, digest: &H::Digest) -> Option<MessageRef<'a, H::Digest>> {
+        self.messages.get(digest).map(|stored| {
+            MessageRef {
+                digest: *digest,
+                stored,
+            }
+        })
+    }
+
+    /// Returns borrowed views of all held messages, in order of hash.
+    pub fn iter_messages<'a>(&'a self) -> impl Iterator<Item = MessageRef<'a, H::Digest>> + 'a {
+        self.messages
+            .iter()
+            .map(|(digest, stored)| {
+                MessageRef {
+                    digest: *digest,
+                    stored,
+                }
+            })
+    }
+
+    /// Returns the counters received from each peer this round for the message with the given hash,
+    /// or `None` if it isn't held or isn't in state B.  These are the counters to which the median
+    /// rule will be applied at the end of the round, not including the `0`s then added for peers
+    /// contacted which didn't send the message.
+    pub fn peer_counters(&self, digest: &H::Digest) -> Option<&BTreeMap<Id, u8>> {
+        match self.messages.get(digest).map(|stored| &stored.state) {
+            Some(&MessageState::B { ref peer_counters, .. }) => Some(peer_counters),
+            _ => None,
+        }
+    }
+
+    /// Returns the phase of the message with the given hash, or `None` if it isn't held.
+    pub fn message_phase(&self, digest: &H::Digest) -> Option<Phase> {
+        self.messages.get(digest).map(|stored| stored.state.phase())
+    }
+
+    /// Returns whether the message with the given hash was started by this node or learned from a
+    /// peer, or `None` if it isn't held.  A message started here remains `Local` when copies are
+    /// subsequently received from peers.
+    pub fn origin(&self, digest: &H::Digest) -> Option<Origin> {
+        self.messages.get(digest).map(|stored| stored.origin)
+    }
+
+    /// Returns the hashes of all held messages, whatever their phase.  Two nodes can diff these
+    /// sets to find which payloads each lacks before transferring any.
+    pub fn digest_set(&self) -> BTreeSet<H::Digest> {
         self.messages.keys().cloned().collect()
     }
 
-    /// Start gossiping a new message from this node.
-    pub fn new_message(&mut self, msg: Vec<u8>) {
-        if self.messages.insert(msg, MessageState::new()).is_some() {
-            error!("New messages should be unique.");
+    /// Returns those of the given hashes whose messages aren't held, in the order given, e.g. to
+    /// decide which to request from a peer.  Tombstoned hashes aren't included, since copies of
+    /// those messages would be ignored anyway (see `set_tombstone_rounds()`).
+    pub fn missing<'a, I>(&self, digests: I) -> Vec<H::Digest>
+    where
+        I: IntoIterator<Item = &'a H::Digest>,
+        H::Digest: 'a,
+    {
+        digests
+            .into_iter()
+            .filter(|digest| !self.has_message(digest) && !self.is_tombstoned(digest))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the hashes of all held messages currently in the given phase, in ascending order.
+    pub fn messages_in_phase(&self, phase: Phase) -> Vec<H::Digest> {
+        self.messages
+            .iter()
+            .filter(|&(_, stored)| stored.state.phase() == phase)
+            .map(|(digest, _)| *digest)
+            .collect()
+    }
+
+    /// Returns the hash of each held message along with its progress towards termination, from
+    /// `0.0` when just started to `1.0` once terminated.  This is its total rounds in states B and
+    /// C as a fraction of `max_rounds()`, as that's the latest it can terminate.  The list is
+    /// sorted by ascending progress, with ties ordered by hash.
+    pub fn messages_by_progress(&self) -> Vec<(H::Digest, f32)> {
+        let max_rounds = f32::from(self.max_rounds);
+        let mut progress: Vec<_> = self.messages
+            .iter()
+            .map(|(digest, stored)| {
+                // Terminated messages have no rounds, and a zero `max_rounds` terminates at once.
+                match stored.state.rounds() {
+                    Some(rounds) if max_rounds > 0.0 => {
+                        (*digest, (f32::from(rounds) / max_rounds).min(1.0))
+                    }
+                    _ => (*digest, 1.0),
+                }
+            })
+            .collect();
+        sort_ties_by_hash(&mut progress, |&(_, progress)| progress);
+        progress
+    }
+
+    /// Returns `true` if the message with the given hash is held and its propagation is complete.
+    ///
+    /// A message's propagation completes (i.e. it moves to state D and is no longer included in
+    /// any Push or Pull RPCs) at the end of the first round in which either its rounds in state C
+    /// reach `max_c_rounds()`, or its total rounds in states B and C reach `max_rounds()`.
+    pub fn is_terminated(&self, digest: &H::Digest) -> bool {
+        self.message_phase(digest) == Some(Phase::D)
+    }
+
+    /// Returns `true` if propagation of every held message is complete.
+    pub fn all_terminated(&self) -> bool {
+        self.messages
+            .values()
+            .all(|stored| stored.state.phase() == Phase::D)
+    }
+
+    /// Removes every message whose propagation is complete, returning the number removed.  Note
+    /// that a removed message will be treated as new if it is subsequently received again, unless
+    /// tombstones are enabled via `set_tombstone_rounds()`.
+    pub fn prune_terminated(&mut self) -> usize {
+        let count = self.messages.len();
+        let round = self.current_round();
+        let keep_tombstones = self.config.tombstone_rounds.is_some();
+        let messages = mem::replace(&mut self.messages, BTreeMap::new());
+        let (terminated, remaining): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .partition(|&(_, ref stored)| stored.state.phase() == Phase::D);
+        self.messages = remaining.into_iter().collect();
+        if keep_tombstones {
+            for (digest, _) in terminated {
+                let _ = self.tombstones.insert(digest, round);
+            }
+        }
+        count - self.messages.len()
+    }
+
+    /// Removes every message for which `f` returns `false` when passed its hash, content and state,
+    /// returning the number removed.  This allows custom eviction policies; as with the others, a
+    /// removed message will be treated as new if it is subsequently received again.  Messages
+    /// which can't be decompressed are kept.
+    pub fn retain<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&H::Digest, &[u8], &MessageState) -> bool,
+    {
+        let count = self.messages.len();
+        let messages = mem::replace(&mut self.messages, BTreeMap::new());
+        self.messages = messages
+            .into_iter()
+            .filter(|&(ref digest, ref stored)| {
+                payload(stored).map_or(true, |msg| f(digest, &msg, &stored.state))
+            })
+            .collect();
+        count - self.messages.len()
+    }
+
+    /// Removes every message first learned more than the maximum age (see `set_max_age_rounds()`)
+    /// before `now_round`, returning the number removed.  A message first learned exactly the
+    /// maximum age ago is kept.  Does nothing if no maximum age is set.
+    pub fn prune_expired(&mut self, now_round: u64) -> usize {
+        let max_age_rounds = match self.config.max_age_rounds {
+            Some(max_age_rounds) => max_age_rounds,
+            None => return 0,
+        };
+        let count = self.messages.len();
+        let messages = mem::replace(&mut self.messages, BTreeMap::new());
+        self.messages = messages
+            .into_iter()
+            .filter(|&(_, ref stored)| {
+                now_round.saturating_sub(stored.first_round) <= max_age_rounds
+            })
+            .collect();
+        count - self.messages.len()
+    }
+
+    /// Stops gossiping the message with the given hash immediately, regardless of its state.
+    /// Returns `false` if the message wasn't held.
+    pub fn remove_message(&mut self, digest: &H::Digest) -> bool {
+        self.messages.remove(digest).is_some()
+    }
+
+    /// Restarts propagation of the message with the given hash as if it had just been created by
+    /// this node, regardless of its state, e.g. to reach a newly joined part of the network.  Its
+    /// other details are kept.  Returns `false` if the message wasn't held.
+    ///
+    /// If the message has already been returned to state B the maximum number of times (see
+    /// `set_max_rehot()`), it's terminated instead.
+    pub fn refresh(&mut self, digest: &H::Digest) -> bool {
+        let round = self.current_round();
+        let max_rehot = self.config.max_rehot;
+        let terminated = match self.messages.get_mut(digest) {
+            Some(stored) => {
+                let old_phase = stored.state.phase();
+                let rehot = old_phase != Phase::B;
+                if rehot && max_rehot.map_or(false, |max_rehot| stored.rehot_count >= max_rehot) {
+                    stored.state = MessageState::D;
+                } else {
+                    if rehot {
+                        stored.rehot_count = stored.rehot_count.saturating_add(1);
+                    }
+                    stored.state = MessageState::new();
+                    stored.last_changed_round = round;
+                }
+                old_phase == Phase::C && stored.state.phase() == Phase::D
+            }
+            None => return false,
+        };
+        if terminated {
+            self.push_event(GossipEvent::Terminated(*digest));
+        }
+        true
+    }
+
+    /// Returns the number of rounds since we first learned of the message with the given hash, or
+    /// `None` if it isn't held.
+    pub fn message_age_rounds(&self, digest: &H::Digest) -> Option<u64> {
+        self.messages
+            .get(digest)
+            .map(|stored| self.current_round().saturating_sub(stored.first_round))
+    }
+
+    /// Returns the number of further rounds after which the message with the given hash will have
+    /// reached `max_rounds()`, and so be terminated even if its counter never advances, or `None`
+    /// if it isn't held or is already terminated.  It may terminate sooner, e.g. once it has spent
+    /// `max_c_rounds()` in state C.
+    pub fn rounds_until_terminate(&self, digest: &H::Digest) -> Option<u8> {
+        let rounds = self.messages.get(digest)?.state.rounds()?;
+        Some(self.max_rounds.saturating_sub(rounds))
+    }
+
+    /// Merges the messages held by `other` into ours, e.g. when reconciling after a partition
+    /// heals.  Messages we don't hold are stored as if received from a peer, so tombstoned ones
+    /// are ignored and others may be evicted to make room; for those we do, the state furthest
+    /// through propagation is kept.  If `other` holds a different message under the hash of one of
+    /// ours, or one which can't be decompressed or, in signed mode, isn't signed, an error is
+    /// returned without any being merged.
+    pub fn merge(&mut self, other: &Gossip<H>) -> Result<(), Error> {
+        let mut msgs = Vec::with_capacity(other.messages.len());
+        for (digest, stored) in &other.messages {
+            let msg = stored.payload()?;
+            self.check_collision(digest, &msg)?;
+            self.check_signature(&msg)?;
+            msgs.push((*digest, msg, &stored.state));
+        }
+        for (digest, msg, state) in msgs {
+            if self.tombstones.contains_key(&digest) {
+                continue;
+            }
+            if let Some(stored) = self.messages.get_mut(&digest) {
+                stored.state.merge(state);
+                continue;
+            }
+            self.store_message(digest, msg, state.clone(), Origin::Remote)?;
+        }
+        Ok(())
+    }
+
+    /// Returns copies of all held messages.
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.messages_iter().map(|msg| msg.to_vec()).collect()
+    }
+
+    /// Removes all held messages along with their states, returning each with its hash in order of
+    /// hash, e.g. to hand them over to a successor node.  Unlike `clear()`, this keeps the
+    /// statistics.  If any message fails to decompress, the error is returned and none are removed.
+    pub fn drain_messages(&mut self) -> Result<Vec<(H::Digest, Vec<u8>)>, Error> {
+        let drained = self.messages
+            .iter()
+            .map(|(digest, stored)| Ok((*digest, stored.payload()?.to_vec())))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.messages.clear();
+        self.median_bumps.clear();
+        self.skipped.clear();
+        Ok(drained)
+    }
+
+    /// Iterates over the held messages without copying them, unless they're held compressed.
+    pub fn messages_iter<'a>(&'a self) -> impl Iterator<Item = Arc<[u8]>> + 'a {
+        self.messages.values().filter_map(|stored| payload(stored))
+    }
+
+    /// Calls `f` with the hash and content of each held message in turn, in order of hash.
+    pub fn for_each_message<F>(&self, mut f: F)
+    where
+        F: FnMut(&H::Digest, &[u8]),
+    {
+        for (digest, stored) in &self.messages {
+            if let Some(msg) = payload(stored) {
+                f(digest, &msg);
+            }
+        }
+    }
+
+    /// Start gossiping a new message from this node.  Returns the hash of the message.  If the
+    /// message is already known, its state is left untouched.  Likewise, a message which has been
+    /// pruned but is still tombstoned (see `set_tombstone_rounds()`) isn't gossiped again; use
+    /// `new_message_forced()` for that.
+    pub fn new_message(&mut self, msg: Vec<u8>) -> Result<H::Digest, Error> {
+        if msg.is_empty() {
+            return Err(Error::Empty);
+        }
+        self.check_size(&msg)?;
+        self.check_signature(&msg)?;
+        let digest = H::hash(&msg);
+        self.insert_new_message(digest, msg)
+    }
+
+    /// Start gossiping a new message from this node as per `new_message()`, but even if it's
+    /// tombstoned, in which case the tombstone is removed.
+    pub fn new_message_forced(&mut self, msg: Vec<u8>) -> Result<H::Digest, Error> {
+        if msg.is_empty() {
+            return Err(Error::Empty);
+        }
+        self.check_size(&msg)?;
+        self.check_signature(&msg)?;
+        let digest = H::hash(&msg);
+        let _ = self.tombstones.remove(&digest);
+        self.insert_new_message(digest, msg)
+    }
+
+    /// Start gossiping a new message from this node as per `new_message()`, but trusting `digest`
+    /// as its hash rather than computing it, e.g. where it was computed incrementally via a
+    /// `MessageHasher` as the message was streamed in.
+    ///
+    /// The caller is responsible for `digest` being correct.  If it isn't, peers will identify the
+    /// message by its true hash, so our state for it will never be advanced by theirs, and a
+    /// different message which truly has that hash will be treated as a collision.
+    pub fn new_message_prehashed(
+        &mut self,
+        digest: H::Digest,
+        msg: Vec<u8>,
+    ) -> Result<H::Digest, Error> {
+        if msg.is_empty() {
+            return Err(Error::Empty);
+        }
+        self.check_size(&msg)?;
+        self.check_signature(&msg)?;
+        self.insert_new_message(digest, msg)
+    }
+
+    fn insert_new_message(&mut self, digest: H::Digest, msg: Vec<u8>) -> Result<H::Digest, Error> {
+        if self.tombstones.contains_key(&digest) {
+            debug!("Not restarting a pruned terminated message");
+            return Ok(digest);
+        }
+        self.check_collision(&digest, &msg)?;
+        if self.messages.contains_key(&digest) {
+            debug!("Already holding this new message");
+        } else {
+            self.store_message(digest, msg.into(), MessageState::new(), Origin::Local)?;
+        }
+        Ok(digest)
+    }
+
+    // Stores `msg`, which isn't already held, under `digest`, then evicts others if needed to make
+    // room for it.
+    fn store_message(
+        &mut self,
+        digest: H::Digest,
+        msg: Arc<[u8]>,
+        state: MessageState,
+        origin: Origin,
+    ) -> Result<(), Error> {
+        let round = self.current_round();
+        let stored = StoredMessage::new(msg, state, origin, round, self.config.compress_payloads)?;
+        let _ = self.messages.insert(digest, stored);
+        self.push_event(GossipEvent::NewMessage(digest));
+        self.evict_for_space(&digest);
+        Ok(())
+    }
+
+    // Stores `msg` as if just started by this node with `counter`, unless it's already held.
+    fn preload_message(&mut self, counter: u8, msg: Vec<u8>) -> Result<(), Error> {
+        if msg.is_empty() {
+            return Err(Error::Empty);
+        }
+        self.check_size(&msg)?;
+        self.check_signature(&msg)?;
+        let digest = H::hash(&msg);
+        self.check_collision(&digest, &msg)?;
+        if self.messages.contains_key(&digest) {
+            return Ok(());
+        }
+        let state = MessageState::B {
+            round: 0,
+            our_counter: cmp::max(1, counter),
+            peer_counters: BTreeMap::new(),
+        };
+        self.store_message(digest, msg.into(), state, Origin::Local)
+    }
+
+    /// Start gossiping several new messages from this node, returning their hashes in the same
+    /// order.  As with `new_message()`, a message already held is left as it is, so duplicates are
+    /// harmless.  If any message is invalid, an error is returned without any being added.
+    pub fn new_messages(&mut self, msgs: Vec<Vec<u8>>) -> Result<Vec<H::Digest>, Error> {
+        let mut batch = BTreeMap::new();
+        for msg in &msgs {
+            if msg.is_empty() {
+                return Err(Error::Empty);
+            }
+            self.check_size(msg)?;
+            self.check_signature(msg)?;
+            let digest = H::hash(msg);
+            self.check_collision(&digest, msg)?;
+            if batch.insert(digest, msg).map_or(false, |other| other != msg) {
+                return Err(Error::DigestCollision);
+            }
         }
+        msgs.into_iter().map(|msg| self.new_message(msg)).collect()
     }
 
     /// Trigger the end of this round.  Returns a list of Push RPCs to be sent to a single random
     /// peer during this new round.
     pub fn next_round(&mut self) -> Vec<GossipRpc> {
+        let push_list = self.next_round_detailed();
+        self.to_push_rpcs(push_list)
+    }
+
+    /// Returns the Push RPCs which `next_round()` would return if called now, without affecting
+    /// any state or statistics.  As with `next_round()`, a single empty Push is returned if there
+    /// is nothing to push.
+    pub fn peek_next_round(&self) -> Vec<GossipRpc> {
+        let mut push_list: Vec<GossipRpc> = self.peek_states()
+            .filter_map(|(stored, new_state)| {
+                Some(GossipRpc::Push {
+                    counter: new_state.our_counter()?,
+                    msg: payload(stored)?,
+                })
+            })
+            .collect();
+        if push_list.is_empty() {
+            push_list.push(GossipRpc::Push {
+                msg: Vec::new().into(),
+                counter: 0,
+            });
+        }
+        push_list
+    }
+
+    /// Returns the number of messages which `next_round()` would push if called now, without
+    /// affecting any state or building the RPCs.  If this is `0`, a single empty Push is sent.
+    pub fn pending_push_count(&self) -> usize {
+        self.peek_states()
+            .filter(|&(_, ref new_state)| new_state.our_counter().is_some())
+            .count()
+    }
+
+    /// Returns the number of messages which would be sent back in Pull RPCs in response to a Push
+    /// from a peer not yet heard from this round, without affecting any state.  If this is `0`, a
+    /// single empty Pull is sent instead.
+    pub fn pull_list_len(&self) -> usize {
+        self.pull_eligible().count()
+    }
+
+    /// Returns the hashes of the messages which would be sent back in Pull RPCs in response to a
+    /// Push from a peer not yet heard from this round, in order of hash and without affecting any
+    /// state.
+    pub fn pull_list_digests(&self) -> Vec<H::Digest> {
+        self.pull_eligible().map(|(digest, _)| *digest).collect()
+    }
+
+    /// Returns Pull RPCs for just those of the `wanted` hashes whose messages would be included in
+    /// the pull list, in the order requested and without affecting any state.  This allows sending
+    /// only the payloads a peer is known to lack, e.g. after diffing `digest_set()`s.  Hashes of
+    /// messages which aren't held or are in state D are omitted.
+    pub fn pull_list_for(&self, wanted: &[H::Digest]) -> Vec<GossipRpc> {
+        wanted
+            .iter()
+            .filter_map(|digest| {
+                let stored = self.messages.get(digest)?;
+                let counter = stored.state.our_counter()?;
+                let msg = payload(stored)?;
+                Some(GossipRpc::Pull { msg, counter })
+            })
+            .collect()
+    }
+
+    /// Returns Pull RPCs for at most `max_items` of the messages which would be included in the
+    /// pull list, without affecting any state.  When bandwidth is limited, this favours the least
+    /// spread: they're chosen in order of ascending counter, with ties ordered by hash.
+    pub fn pull_list_limited(&self, max_items: usize) -> Vec<GossipRpc> {
+        let mut eligible: Vec<_> = self.pull_eligible()
+            .filter_map(|(_, stored)| Some((stored.state.our_counter()?, stored)))
+            .collect();
+        sort_ties_by_hash(&mut eligible, |&(counter, _)| counter);
+        eligible
+            .into_iter()
+            .filter_map(|(counter, stored)| {
+                Some(GossipRpc::Pull {
+                    msg: payload(stored)?,
+                    counter,
+                })
+            })
+            .take(max_items)
+            .collect()
+    }
+
+    /// Trigger the end of this round as per `next_round()`, advancing every message as usual, but
+    /// only return Push RPCs for messages whose `last_changed_round` is after `since_round`, e.g.
+    /// to avoid re-pushing unchanged messages to a peer which reconnects having already been sent
+    /// everything up to that round.  A single empty Push is returned if there is nothing to push.
+    pub fn next_round_since(&mut self, since_round: u64) -> Vec<GossipRpc> {
+        let push_list: Vec<_> = self.end_round(true)
+            .into_iter()
+            .filter(|item| {
+                self.messages
+                    .get(&item.digest)
+                    .map_or(false, |stored| stored.last_changed_round > since_round)
+            })
+            .collect();
+        self.count_pushed(&push_list);
+        self.to_push_rpcs(push_list)
+    }
+
+    /// Trigger the end of this round as per `next_round()`, but order the Push RPCs by ascending
+    /// counter so that the least-spread messages come first.  Ties are ordered by hash.
+    pub fn next_round_sorted_by_counter(&mut self) -> Vec<GossipRpc> {
+        let mut push_list = self.next_round_detailed();
+        sort_ties_by_hash(&mut push_list, |item| item.counter);
+        self.to_push_rpcs(push_list)
+    }
+
+    /// Trigger the end of this round as per `next_round()`, but return the full details of each
+    /// message to be pushed rather than the RPCs.  Unlike `next_round()`, no empty Push is
+    /// included if there is nothing to push.
+    pub fn next_round_detailed(&mut self) -> Vec<PushItem<H::Digest>> {
+        let push_list = self.end_round(true);
+        self.count_pushed(&push_list);
+        push_list
+    }
+
+    /// Trigger the end of this round as per `next_round()`, advancing every message's state, but
+    /// without producing any Push RPCs or counting any as sent.  This allows rounds to be driven by
+    /// a timer, so that messages still progress towards termination while nothing is being sent.
+    /// Since nothing is pushed, the push budget (see `set_max_push_per_round()`) doesn't apply.
+    pub fn advance_round(&mut self) {
+        let _ = self.end_round(false);
+    }
+
+    // Ends the round, returning the details of each message due to be pushed in the new one.  If
+    // `budgeted` is false, every message is advanced regardless of `max_push_per_round`.
+    fn end_round(&mut self, budgeted: bool) -> Vec<PushItem<H::Digest>> {
+        let ending_round = self.current_round();
         self.statistics.rounds += 1;
         let mut push_list = vec![];
+        let mut events = vec![];
+        let mut median_bumps = vec![];
+        let mut skipped = vec![];
+        let record_hits = self.config.hit_histogram_enabled;
+        let mut hit_histogram = mem::replace(&mut self.hit_histogram, Vec::new());
+        let selected = match self.config.max_push_per_round {
+            Some(max_push_per_round) if budgeted => Some(self.select_for_push(max_push_per_round)),
+            _ => None,
+        };
         let messages = mem::replace(&mut self.messages, BTreeMap::new());
         self.messages = messages
             .into_iter()
-            .map(|(message, state)| {
-                let new_state = state.next_round(
-                    self.counter_max,
-                    self.max_c_rounds,
-                    self.max_rounds,
-                    &self.peers_in_this_round,
-                );
+            .map(|(digest, mut stored)| {
+                // Hold back any not selected for this round's push budget.
+                if let Some(ref selected) = selected {
+                    if !selected.contains(&digest) {
+                        if stored.state.our_counter().is_some() {
+                            skipped.push(digest);
+                        }
+                        return (digest, stored);
+                    }
+                }
+                if let (true, &MessageState::B { ref peer_counters, .. }) =
+                    (record_hits, &stored.state)
+                {
+                    let hits = peer_counters.len();
+                    if hit_histogram.len() <= hits {
+                        hit_histogram.resize(hits + 1, 0);
+                    }
+                    hit_histogram[hits] = hit_histogram[hits].saturating_add(1);
+                }
+                let state = mem::replace(&mut stored.state, MessageState::D);
+                let old_phase = state.phase();
+                let (new_state, bumped) = state.next_round_with_rule(&self.round_params());
+                if bumped {
+                    stored.last_changed_round = ending_round;
+                    median_bumps.push(digest);
+                }
+                match (old_phase, new_state.phase()) {
+                    (Phase::B, Phase::C) => events.push(GossipEvent::EnteredC(digest)),
+                    (Phase::B, Phase::D) |
+                    (Phase::C, Phase::D) => events.push(GossipEvent::Terminated(digest)),
+                    _ => (),
+                }
                 // Filter out any for which `our_counter()` is `None`.
-                if let Some(counter) = new_state.our_counter() {
-                    push_list.push(GossipRpc::Push {
-                        msg: message.clone(),
+                if let (Some(counter), Some(msg)) = (new_state.our_counter(), payload(&stored)) {
+                    push_list.push(PushItem {
+                        digest,
                         counter,
+                        state: new_state.clone(),
+                        msg,
                     });
                 }
-                (message, new_state)
+                stored.state = new_state;
+                (digest, stored)
             })
             .collect();
-        self.peers_in_this_round.clear();
-        self.statistics.full_message_sent += push_list.len() as u64;
-        // Sends an empty Push in case of nothing to push. It acts as a fetch request to peer.
-        if push_list.is_empty() {
-            self.statistics.empty_push_sent += 1;
-            push_list.push(GossipRpc::Push {
-                msg: Vec::new(),
-                counter: 0,
-            });
+        for event in events {
+            self.push_event(event);
         }
+        self.median_bumps = median_bumps;
+        self.skipped = skipped;
+        self.hit_histogram = hit_histogram;
+        self.peers_in_this_round.clear();
+        self.peer_weights.clear();
+        self.expire_tombstones();
         push_list
     }
 
     /// We've received `rpc` from `peer_id`.  If this is a Push RPC and we've not already heard from
     /// `peer_id` in this round, this returns the list of Pull RPCs which should be sent back to
-    /// `peer_id`.
-    pub fn receive(&mut self, peer_id: Id, rpc: GossipRpc) -> Vec<GossipRpc> {
-        let (is_push, message, counter) = match rpc {
-            GossipRpc::Push { msg, counter } => (true, msg, counter),
-            GossipRpc::Pull { msg, counter } => (false, msg, counter),
+    /// `peer_id`.  Both Push and Pull RPCs update our state for the message they carry, so the Pull
+    /// RPCs sent back in response to our own Pushes should be passed here too.
+    ///
+    /// Serving Pulls is not side-effect free: `peer_id` is recorded as having been contacted this
+    /// round, so at the end of the round it counts towards the median rule for every message in
+    /// state B, with a counter of `0` for any it didn't send us.
+    ///
+    /// An RPC carrying an empty message with a non-zero counter, a message larger than the
+    /// permitted maximum or, in signed mode (see `set_signed()`), an unsigned message is rejected
+    /// without affecting any state.
+    pub fn receive(&mut self, peer_id: Id, rpc: GossipRpc) -> Result<Vec<GossipRpc>, Error> {
+        self.receive_filtered(peer_id, rpc, |_, _| true)
+    }
+
+    /// We've received `rpc` from `peer_id`.  As per `receive()`, except that only messages for
+    /// which `filter` returns `true` when passed their hash and content are included in any Pull
+    /// RPCs returned.  If none are, a single empty Pull is returned.
+    pub fn receive_filtered<F>(
+        &mut self,
+        peer_id: Id,
+        rpc: GossipRpc,
+        filter: F,
+    ) -> Result<Vec<GossipRpc>, Error>
+    where
+        F: Fn(&H::Digest, &[u8]) -> bool,
+    {
+        self.check_rpc(&rpc)?;
+        let (kind, message, counter) = match rpc {
+            GossipRpc::Push { msg, counter } => (RpcKind::Push, msg, counter),
+            GossipRpc::Pull { msg, counter } => (RpcKind::Pull, msg, counter),
+        };
+        self.receive_message(peer_id, kind, message, counter, filter)
+    }
+
+    /// We've received an RPC of the given kind from `peer_id`, carrying `msg` and `counter`.  This
+    /// is equivalent to `receive()`, but borrows the message, only copying it if it's new to us.
+    /// Since peers typically send many copies of each message, this saves an allocation and copy
+    /// for each copy of a message we already hold, e.g. when handling RPCs decoded in place from a
+    /// network buffer.
+    pub fn receive_ref(
+        &mut self,
+        peer_id: Id,
+        kind: RpcKind,
+        counter: u8,
+        msg: &[u8],
+    ) -> Result<Vec<GossipRpc>, Error> {
+        self.check_message(msg, counter)?;
+        self.receive_message(peer_id, kind, msg, counter, |_, _| true)
+    }
+
+    fn receive_message<M, F>(
+        &mut self,
+        peer_id: Id,
+        kind: RpcKind,
+        message: M,
+        counter: u8,
+        filter: F,
+    ) -> Result<Vec<GossipRpc>, Error>
+    where
+        M: AsRef<[u8]> + Into<Arc<[u8]>>,
+        F: Fn(&H::Digest, &[u8]) -> bool,
+    {
+        // Empty Push & Pull shall not be inserted into cache.
+        let digest = if message.as_ref().is_empty() && counter == 0 {
+            None
+        } else {
+            let digest = H::hash(message.as_ref());
+            self.check_collision(&digest, message.as_ref())?;
+            Some(digest)
         };
 
         // Collect any responses required.
         let is_new_this_round = self.peers_in_this_round.insert(peer_id);
-        let responses = if is_new_this_round && is_push {
-            let mut responses: Vec<GossipRpc> = self.messages
-                .iter()
-                .filter_map(|(message, state)| {
-                    // Filter out any for which `our_counter()` is `None`.
-                    state.our_counter().map(|counter| {
-                        GossipRpc::Pull {
-                            msg: message.clone(),
-                            counter,
-                        }
-                    })
+        let responses = if is_new_this_round && kind == RpcKind::Push {
+            let mut responses: Vec<GossipRpc> = self.pull_eligible()
+                .filter_map(|(digest, stored)| {
+                    let counter = stored.state.our_counter()?;
+                    let msg = payload(stored)?;
+                    if !filter(digest, &msg) {
+                        return None;
+                    }
+                    Some(GossipRpc::Pull { msg, counter })
                 })
                 .collect();
             self.statistics.full_message_sent += responses.len() as u64;
+            let bytes_pulled = total_len(responses.iter().map(|rpc| match *rpc {
+                GossipRpc::Push { ref msg, .. } | GossipRpc::Pull { ref msg, .. } => msg,
+            }));
+            self.statistics.bytes_pulled =
+                self.statistics.bytes_pulled.saturating_add(bytes_pulled);
             // Empty Pull notifies the peer that all messages in this node was in State A.
             if responses.is_empty() {
                 self.statistics.empty_pull_sent += 1;
                 responses.push(GossipRpc::Pull {
-                    msg: Vec::new(),
+                    msg: Vec::new().into(),
                     counter: 0,
                 });
             }
@@ -150,48 +1111,556 @@ impl Gossip {
             vec![]
         };
 
-        // Empty Push & Pull shall not be inserted into cache.
-        if !(message.is_empty() && counter == 0) {
+        if let Some(digest) = digest {
+            if self.tombstones.contains_key(&digest) {
+                debug!("Ignoring a copy of a pruned terminated message");
+                return Ok(responses);
+            }
+            let counter = self.config.trusted_counter_cap
+                .map_or(counter, |cap| cmp::min(counter, cap));
             self.statistics.full_message_received += 1;
             // Add or update the entry for this message.
-            match self.messages.entry(message) {
-                Entry::Occupied(mut entry) => entry.get_mut().receive(peer_id, counter),
-                Entry::Vacant(entry) => {
-                    let _ = entry.insert(MessageState::new_from_peer(counter, self.counter_max));
+            let max_hits = self.max_hits_per_round();
+            if let Some(stored) = self.messages.get_mut(&digest) {
+                if !stored.state.receive(peer_id, counter, max_hits) {
+                    self.statistics.hits_dropped += 1;
                 }
+                return Ok(responses);
             }
+            let state = MessageState::new_from_peer(counter, self.counter_max);
+            self.store_message(digest, message.into(), state, Origin::Remote)?;
         }
 
-        responses
+        Ok(responses)
     }
 
-    #[cfg(test)]
-    /// Clear the cache.
-    pub fn clear(&mut self) {
-        self.statistics = Statistics::default();
-        self.messages.clear();
-        self.peers_in_this_round.clear();
+    /// As per `receive()`, but `peer_id`'s counters carry `weight` rather than `1` in the median
+    /// rule applied at the end of this round, e.g. to favour peers known to be more reliable.  The
+    /// weight applies to all of the peer's counters this round, with the latest given taking
+    /// precedence.
+    pub fn receive_weighted(
+        &mut self,
+        peer_id: Id,
+        rpc: GossipRpc,
+        weight: u32,
+    ) -> Result<Vec<GossipRpc>, Error> {
+        let responses = self.receive(peer_id, rpc)?;
+        if weight == 1 {
+            let _ = self.peer_weights.remove(&peer_id);
+        } else {
+            let _ = self.peer_weights.insert(peer_id, weight);
+        }
+        Ok(responses)
     }
 
-    /// Returns the statistics.
-    pub fn statistics(&self) -> Statistics {
-        self.statistics
+    /// We've received all of `rpcs` from `peer_id`.  This is equivalent to passing each to
+    /// `receive()` in order and concatenating the responses, except that if any is invalid, an
+    /// error is returned without any of them affecting our state.
+    pub fn receive_batch<I>(&mut self, peer_id: Id, rpcs: I) -> Result<Vec<GossipRpc>, Error>
+    where
+        I: IntoIterator<Item = GossipRpc>,
+    {
+        let rpcs: Vec<GossipRpc> = rpcs.into_iter().collect();
+        let mut batch = BTreeMap::new();
+        for rpc in &rpcs {
+            self.check_rpc(rpc)?;
+            let msg = match *rpc {
+                GossipRpc::Push { ref msg, .. } | GossipRpc::Pull { ref msg, .. } => msg,
+            };
+            if msg.is_empty() {
+                continue;
+            }
+            // Check for collisions with messages held and with others in the batch.
+            let digest = H::hash(msg);
+            self.check_collision(&digest, msg)?;
+            if batch.insert(digest, msg).map_or(false, |other| other != msg) {
+                return Err(Error::DigestCollision);
+            }
+        }
+        let mut responses = vec![];
+        for rpc in rpcs {
+            responses.extend(self.receive(peer_id, rpc)?);
+        }
+        Ok(responses)
+    }
+
+    /// Applies a single abstract operation, returning any RPCs due to be sent as a result.  This
+    /// gives a single entry point through which property tests and fuzzers can drive arbitrary
+    /// sequences of operations, checking `verify_invariants()` after each.
+    pub fn apply_op(&mut self, op: GossipOp) -> Result<Vec<GossipRpc>, Error> {
+        match op {
+            GossipOp::NewMessage(msg) => self.new_message(msg).map(|_| vec![]),
+            GossipOp::Receive(peer_id, rpc) => self.receive(peer_id, rpc),
+            GossipOp::NextRound => Ok(self.next_round()),
+            GossipOp::PullList => Ok(self.pull_list_for(&self.pull_list_digests())),
+            GossipOp::AddPeer => {
+                self.add_peer();
+                Ok(vec![])
+            }
+            GossipOp::RemovePeer => {
+                self.remove_peer();
+                Ok(vec![])
+            }
+        }
+    }
+
+    /// Clear the cache and statistics.  The network size and the round thresholds derived from it
+    /// are kept.
+    pub fn clear(&mut self) {
+        self.statistics = Statistics::default();
+        self.hit_histogram.clear();
+        self.messages.clear();
+        self.peers_in_this_round.clear();
+        self.peer_weights.clear();
+        self.tombstones.clear();
+        self.median_bumps.clear();
+        self.skipped.clear();
+    }
+
+    /// Clear the cache and statistics as per `clear()`, and also reset the network size to just
+    /// this node, forgetting any registered peers.
+    pub fn reset(&mut self) {
+        self.clear();
+        self.peer_ids.clear();
+        self.set_total_peers(0);
+    }
+
+    /// Returns the number of rounds started so far, i.e. the number of times `next_round()` or one
+    /// of its variants has been called since construction or the last `clear()`.
+    pub fn current_round(&self) -> u64 {
+        self.statistics.rounds
+    }
+
+    /// Returns the hashes of the messages whose counters were incremented by the median rule at
+    /// the end of the last round, in ascending order.
+    pub fn last_round_median_bumps(&self) -> &[H::Digest] {
+        &self.median_bumps
+    }
+
+    /// Returns the hashes of the messages which were due to be pushed but were held back by the
+    /// push budget (see `set_max_push_per_round()`) at the end of the last round, in ascending
+    /// order.  These take priority in the next round.  For the pull budget of
+    /// `pull_list_limited()`, the messages omitted are simply the rest of `pull_list_digests()`.
+    pub fn last_round_skipped(&self) -> &[H::Digest] {
+        &self.skipped
+    }
+
+    /// Enable or disable recording of the hit histogram returned by `round_hit_histogram()`.  It's
+    /// disabled by default.  Enabling it when already enabled keeps the figures recorded so far,
+    /// while disabling it discards them.
+    pub fn set_hit_histogram_enabled(&mut self, enabled: bool) {
+        self.config.hit_histogram_enabled = enabled;
+        if !enabled {
+            self.hit_histogram.clear();
+        }
+    }
+
+    /// Returns the distribution of how many peers each message was received from per round.  The
+    /// value at index `i` is the number of times a message in state B ended a round having been
+    /// received from `i` distinct peers during it, as recorded since the histogram was enabled via
+    /// `set_hit_histogram_enabled()`.  This is empty if it's disabled.
+    pub fn round_hit_histogram(&self) -> &[u32] {
+        &self.hit_histogram
+    }
+
+    /// Panics with a description of the problem if the internal bookkeeping is inconsistent.  This
+    /// is intended for tests and debugging, so is only available in debug builds, and is relatively
+    /// expensive as it rehashes every held message.  The invariants checked are:
+    ///
+    /// * each held message's hash matches its content, so none is stored under a stale key
+    /// * the round thresholds are all at least `1`
+    /// * unless overridden via `set_rounds()`, the round thresholds match those derived from the
+    ///   current network size and minimums
+    /// * every peer given a weight via `receive_weighted()` has been heard from this round, as
+    ///   weights are discarded along with the round's peers
+    /// * no more than `MAX_EVENTS` events are buffered
+    #[cfg(any(test, debug_assertions))]
+    pub fn verify_invariants(&self) {
+        for (digest, stored) in &self.messages {
+            let problem = match stored.payload() {
+                Ok(ref msg) if H::hash(msg) == *digest => continue,
+                Ok(_) => "doesn't match its hash".to_string(),
+                Err(error) => format!("can't be read: {}", error),
+            };
+            let prefix: String = digest
+                .as_ref()
+                .iter()
+                .take(4)
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            panic!("Held message {}.. {}", prefix, problem);
+        }
+        let rounds = (self.counter_max, self.max_c_rounds, self.max_rounds);
+        assert!(
+            self.counter_max >= 1 && self.max_c_rounds >= 1 && self.max_rounds >= 1,
+            "Round thresholds {:?} include zero",
+            rounds
+        );
+        assert_eq!(
+            rounds,
+            self.config.rounds_override.unwrap_or_else(|| self.derived_rounds()),
+            "Round thresholds are stale for network size {}",
+            self.network_size
+        );
+        assert!(
+            self.config.hit_histogram_enabled || self.hit_histogram.is_empty(),
+            "The hit histogram is recorded while disabled"
+        );
+        for peer_id in self.peer_weights.keys() {
+            assert!(
+                self.peers_in_this_round.contains(peer_id),
+                "Peer {:?} has a weight but hasn't been heard from this round",
+                peer_id
+            );
+        }
+        assert!(
+            self.events.len() <= MAX_EVENTS,
+            "{} events are buffered",
+            self.events.len()
+        );
+    }
+
+    /// Returns the statistics.
+    pub fn statistics(&self) -> Statistics {
+        self.statistics
+    }
+
+    /// Returns the total bytes of messages pushed since the statistics were last cleared.
+    pub fn bytes_pushed(&self) -> u64 {
+        self.statistics.bytes_pushed
+    }
+
+    /// Returns the total bytes of messages sent in Pull RPCs since the statistics were last
+    /// cleared.
+    pub fn bytes_pulled(&self) -> u64 {
+        self.statistics.bytes_pulled
+    }
+
+    /// Returns aggregate figures on the held messages' progress.
+    pub fn stats(&self) -> GossipStats {
+        let mut stats = GossipStats {
+            rounds: self.current_round(),
+            ..GossipStats::default()
+        };
+        for stored in self.messages.values() {
+            match stored.state.phase() {
+                Phase::B => stats.in_state_b += 1,
+                Phase::C => stats.in_state_c += 1,
+                Phase::D => stats.in_state_d += 1,
+            }
+            stats.total_bytes += stored.msg.len() as u64;
+        }
+        stats
+    }
+
+    /// Returns the effective configuration, e.g. for logging or to confirm it survived being
+    /// restored from a snapshot.
+    pub fn config(&self) -> &GossipConfig {
+        &self.config
+    }
+
+    /// Removes and returns all buffered events, oldest first.  At most `MAX_EVENTS` are buffered.
+    pub fn drain_events(&mut self) -> Vec<GossipEvent<H::Digest>> {
+        self.events.drain(..).collect()
+    }
+
+    fn push_event(&mut self, event: GossipEvent<H::Digest>) {
+        if self.events.len() >= MAX_EVENTS {
+            let _ = self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn to_push_rpcs(&mut self, push_list: Vec<PushItem<H::Digest>>) -> Vec<GossipRpc> {
+        let mut push_list: Vec<GossipRpc> = push_list
+            .into_iter()
+            .map(|item| {
+                GossipRpc::Push {
+                    msg: item.msg,
+                    counter: item.counter,
+                }
+            })
+            .collect();
+        // Sends an empty Push in case of nothing to push. It acts as a fetch request to peer.
+        if push_list.is_empty() {
+            self.statistics.empty_push_sent += 1;
+            push_list.push(GossipRpc::Push {
+                msg: Vec::new().into(),
+                counter: 0,
+            });
+        }
+        push_list
+    }
+
+    // Iterates over the messages to be pulled, i.e. those for which `our_counter()` isn't `None`.
+    fn pull_eligible<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = (&'a H::Digest, &'a StoredMessage)> + 'a {
+        self.messages
+            .iter()
+            .filter(|&(_, stored)| stored.state.our_counter().is_some())
+    }
+
+    // Returns each message which would advance at the end of this round, given its push budget,
+    // along with the state it would advance to.
+    fn peek_states<'a>(&'a self) -> impl Iterator<Item = (&'a StoredMessage, MessageState)> + 'a {
+        let selected = self.config.max_push_per_round
+            .map(|max_push_per_round| self.select_for_push(max_push_per_round));
+        self.messages
+            .iter()
+            .filter(move |&(digest, _)| {
+                selected
+                    .as_ref()
+                    .map_or(true, |selected| selected.contains(digest))
+            })
+            .map(move |(_, stored)| {
+                let new_state = stored.state.clone().next_round_with_rule(&self.round_params()).0;
+                (stored, new_state)
+            })
+    }
+
+    fn count_pushed(&mut self, push_list: &[PushItem<H::Digest>]) {
+        self.statistics.full_message_sent += push_list.len() as u64;
+        self.statistics.bytes_pushed = self.statistics
+            .bytes_pushed
+            .saturating_add(total_len(push_list.iter().map(|item| &item.msg)));
+    }
+
+    fn round_params<'a>(&'a self) -> RoundParams<'a> {
+        RoundParams {
+            counter_max: self.counter_max,
+            max_c_rounds: self.max_c_rounds,
+            max_rounds: self.max_rounds,
+            peers_in_this_round: &self.peers_in_this_round,
+            peer_weights: &self.peer_weights,
+            rule: self.config.median_rule,
+            max_hits: self.max_hits_per_round(),
+        }
+    }
+
+    // Selects up to `max_push_per_round` messages to be pushed, ordered by counter, then rounds
+    // elapsed, then hash.
+    fn select_for_push(&self, max_push_per_round: usize) -> BTreeSet<H::Digest> {
+        let mut candidates: Vec<_> = self.messages
+            .iter()
+            .filter_map(|(digest, stored)| {
+                match (stored.state.our_counter(), stored.state.rounds()) {
+                    (Some(counter), Some(rounds)) => Some((counter, rounds, *digest)),
+                    _ => None,
+                }
+            })
+            .collect();
+        candidates.sort();
+        candidates
+            .into_iter()
+            .take(max_push_per_round)
+            .map(|(_, _, digest)| digest)
+            .collect()
+    }
+
+    // Forgets any tombstones held for longer than `tombstone_rounds`.
+    fn expire_tombstones(&mut self) {
+        let tombstone_rounds = match self.config.tombstone_rounds {
+            Some(tombstone_rounds) => tombstone_rounds,
+            None => return,
+        };
+        let now_round = self.current_round();
+        let tombstones = mem::replace(&mut self.tombstones, BTreeMap::new());
+        self.tombstones = tombstones
+            .into_iter()
+            .filter(|&(_, round)| now_round.saturating_sub(round) <= tombstone_rounds)
+            .collect();
+    }
+
+    // Evicts messages other than `keep` until the total size is within `max_total_bytes`.
+    fn evict_for_space(&mut self, keep: &H::Digest) {
+        let max_total_bytes = match self.config.max_total_bytes {
+            Some(max_total_bytes) => max_total_bytes,
+            None => return,
+        };
+        let mut total_bytes = self.total_bytes();
+        if total_bytes <= max_total_bytes {
+            return;
+        }
+        let mut candidates: Vec<_> = self.messages
+            .iter()
+            .filter(|&(digest, _)| digest != keep)
+            .map(|(digest, stored)| {
+                (
+                    cmp::Reverse(stored.state.phase()),
+                    cmp::Reverse(stored.state.rounds()),
+                    *digest,
+                )
+            })
+            .collect();
+        candidates.sort();
+        for (_, _, digest) in candidates {
+            if total_bytes <= max_total_bytes {
+                break;
+            }
+            if let Some(stored) = self.messages.remove(&digest) {
+                total_bytes -= stored.msg.len();
+            }
+        }
+    }
+
+    fn check_rpc(&self, rpc: &GossipRpc) -> Result<(), Error> {
+        match *rpc {
+            GossipRpc::Push { ref msg, counter } |
+            GossipRpc::Pull { ref msg, counter } => self.check_message(msg, counter),
+        }
+    }
+
+    // Returns an error if a message other than `msg` is held under `digest`.
+    fn check_collision(&self, digest: &H::Digest, msg: &[u8]) -> Result<(), Error> {
+        match self.messages.get(digest) {
+            Some(stored) if !stored.matches(msg) => Err(Error::DigestCollision),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_message(&self, msg: &[u8], counter: u8) -> Result<(), Error> {
+        if msg.is_empty() {
+            return if counter == 0 {
+                Ok(())
+            } else {
+                Err(Error::Empty)
+            };
+        }
+        self.check_size(msg)?;
+        self.check_signature(msg)
+    }
+
+    // Returns an error if in signed mode and `msg` isn't validly signed.
+    #[cfg(feature = "signed")]
+    fn check_signature(&self, msg: &[u8]) -> Result<(), Error> {
+        if self.config.signed {
+            let _ = signed::open_signed(msg)?;
+        }
+        Ok(())
+    }
+
+    // Without the "signed" feature, no message can be verified, so in signed mode (e.g. as restored
+    // from a snapshot taken by a build with the feature) all are rejected.
+    #[cfg(not(feature = "signed"))]
+    fn check_signature(&self, _msg: &[u8]) -> Result<(), Error> {
+        if self.config.signed {
+            return Err(Error::SigFailure);
+        }
+        Ok(())
+    }
+
+    fn check_size(&self, msg: &[u8]) -> Result<(), Error> {
+        if msg.len() > self.config.max_message_size {
+            return Err(Error::TooLarge);
+        }
+        Ok(())
+    }
+
+    fn max_hits_per_round(&self) -> usize {
+        self.config.max_hits_per_round.unwrap_or(usize::MAX)
+    }
+
+    fn update_rounds(&mut self) {
+        let (counter_max, max_c_rounds, max_rounds) = self.config
+            .rounds_override
+            .unwrap_or_else(|| self.derived_rounds());
+        self.counter_max = counter_max;
+        self.max_c_rounds = max_c_rounds;
+        self.max_rounds = max_rounds;
+    }
+
+    // Returns `counter_max`, `max_c_rounds` and `max_rounds` as derived from the network size.
+    fn derived_rounds(&self) -> (u8, u8, u8) {
+        // Clamp the intermediate values so that tiny networks (where `ln` or `ln ln` would be zero,
+        // negative or `-inf`) yield the minimum thresholds rather than relying on float casts.
+        let ln = non_negative(self.network_size.ln());
+        let ln_ln = non_negative(ln.ln());
+        let (min_counter_max, min_max_c_rounds, min_max_rounds) = self.config.min_rounds;
+        (
+            cmp::max(min_counter_max, to_rounds(ln_ln)),
+            cmp::max(min_max_c_rounds, to_rounds(ln_ln)),
+            cmp::max(min_max_rounds, to_rounds(ln)),
+        )
+    }
+}
+
+impl<H: Hasher> Gossip<H> {
+    /// Returns a copy of the entire state, from which this instance can be recreated via
+    /// `from_snapshot()`.
+    pub fn snapshot(&self) -> GossipSnapshot<H::Digest> {
+        GossipSnapshot {
+            messages: self.messages.clone(),
+            network_size: self.network_size,
+            peer_ids: self.peer_ids.clone(),
+            config: self.config,
+            tombstones: self.tombstones.clone(),
+            peers_in_this_round: self.peers_in_this_round.clone(),
+            peer_weights: self.peer_weights.clone(),
+            statistics: self.statistics,
+            hit_histogram: self.hit_histogram.clone(),
+        }
+    }
+
+    /// Recreates an instance from `snapshot`, resuming exactly where the original left off.  Fails
+    /// with `Error::CompressionUnsupported` if it holds or is set to store messages compressed but
+    /// this build lacks the "compression" feature.
+    pub fn from_snapshot(snapshot: GossipSnapshot<H::Digest>) -> Result<Self, Error> {
+        let compressed = snapshot.config.compress_payloads ||
+            snapshot.messages.values().any(|stored| stored.compressed);
+        if compressed && !cfg!(feature = "compression") {
+            return Err(Error::CompressionUnsupported);
+        }
+        Ok(Self::restore(snapshot))
+    }
+
+    // As per `from_snapshot()`, but without checking this build can use the snapshot.
+    fn restore(snapshot: GossipSnapshot<H::Digest>) -> Self {
+        let mut gossip = Self::with_config(snapshot.config);
+        gossip.messages = snapshot.messages;
+        gossip.network_size = snapshot.network_size;
+        gossip.peer_ids = snapshot.peer_ids;
+        gossip.tombstones = snapshot.tombstones;
+        gossip.peers_in_this_round = snapshot.peers_in_this_round;
+        gossip.peer_weights = snapshot.peer_weights;
+        gossip.statistics = snapshot.statistics;
+        gossip.hit_histogram = snapshot.hit_histogram;
+        gossip.update_rounds();
+        gossip
+    }
+}
+
+// Implemented manually, as deriving would needlessly require `H: Clone`.
+impl<H: Hasher> Clone for Gossip<H> {
+    fn clone(&self) -> Self {
+        let mut gossip = Gossip::restore(self.snapshot());
+        gossip.events = self.events.clone();
+        gossip.median_bumps = self.median_bumps.clone();
+        gossip.skipped = self.skipped.clone();
+        gossip
+    }
+}
+
+impl<H: Hasher> Default for Gossip<H> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl Debug for Gossip {
+impl<H: Hasher> Debug for Gossip<H> {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         write!(formatter, "Gossip {{ messages: {{ ")?;
-        for (message, state) in &self.messages {
+        for (digest, stored) in &self.messages {
+            write_prefix(formatter, digest.as_ref())?;
             write!(
                 formatter,
-                "{:02x}{:02x}{:02x}{:02x}: {:?}, ",
-                message[0],
-                message[1],
-                message[2],
-                message[3],
-                state
+                ": {:?} {{ counter: {:?}, rounds: {:?}, msg: ",
+                stored.state.phase(),
+                stored.state.our_counter(),
+                stored.state.rounds()
             )?;
+            write_prefix(formatter, &stored.msg)?;
+            write!(formatter, " ({} bytes) }}, ", stored.msg.len())?;
         }
         write!(formatter, "}}, network_size: {}, ", self.network_size)?;
         write!(formatter, "counter_max: {}, ", self.counter_max)?;
@@ -205,9 +1674,475 @@ impl Debug for Gossip {
     }
 }
 
+// Writes the first few bytes of `data` as hex, followed by ".." if any are omitted.
+fn write_prefix(formatter: &mut Formatter, data: &[u8]) -> fmt::Result {
+    for byte in data.iter().take(4) {
+        write!(formatter, "{:02x}", byte)?;
+    }
+    if data.len() > 4 {
+        write!(formatter, "..")?;
+    }
+    Ok(())
+}
+
+// Returns the uncompressed message, or `None` if it can't be decompressed.
+fn payload(stored: &StoredMessage) -> Option<Arc<[u8]>> {
+    match stored.payload() {
+        Ok(msg) => Some(msg),
+        Err(error) => {
+            error!("Failed to decompress message: {}", error);
+            None
+        }
+    }
+}
+
+// Sorts `items`, which are in order of hash, by `key`.  The sort is stable, so items with equal
+// keys remain in order of hash.
+fn sort_ties_by_hash<T, K, F>(items: &mut [T], mut key: F)
+where
+    K: PartialOrd,
+    F: FnMut(&T) -> K,
+{
+    items.sort_by(|lhs, rhs| {
+        key(lhs).partial_cmp(&key(rhs)).unwrap_or(cmp::Ordering::Equal)
+    });
+}
+
+// Returns the total length of `msgs`, saturating at `u64::MAX`.
+fn total_len<'a, I: Iterator<Item = &'a Arc<[u8]>>>(msgs: I) -> u64 {
+    msgs.fold(0, |total: u64, msg| total.saturating_add(msg.len() as u64))
+}
+
+// Returns `value` if it is finite and positive, otherwise `0.0`.
+fn non_negative(value: f64) -> f64 {
+    if value.is_finite() && value > 0.0 {
+        value
+    } else {
+        0.0
+    }
+}
+
+// Rounds `value` up to the nearest integer, saturating at `u8::MAX`.
+fn to_rounds(value: f64) -> u8 {
+    value.ceil().min(f64::from(u8::MAX)) as u8
+}
+
+// Raises each of the thresholds `rounds` to `1` if given as `0`.
+fn at_least_one((counter_max, max_c_rounds, max_rounds): (u8, u8, u8)) -> (u8, u8, u8) {
+    (
+        cmp::max(1, counter_max),
+        cmp::max(1, max_c_rounds),
+        cmp::max(1, max_rounds),
+    )
+}
+
+/// An operation which can be applied via `Gossip::apply_op()`.
+#[derive(Clone, Debug)]
+pub enum GossipOp {
+    /// Start gossiping a new message, as per `Gossip::new_message()`.
+    NewMessage(Vec<u8>),
+    /// Receive an RPC from a peer, as per `Gossip::receive()`.
+    Receive(Id, GossipRpc),
+    /// End the current round, as per `Gossip::next_round()`.
+    NextRound,
+    /// Return the Pull RPCs which would be sent back to a peer not yet heard from this round, as
+    /// per `Gossip::pull_list_for()` given `Gossip::pull_list_digests()`.  This is a query only:
+    /// it has no effect on any state, and in particular neither ends nor advances the round.
+    PullList,
+    /// Increase the network size by one, as per `Gossip::add_peer()`.
+    AddPeer,
+    /// Decrease the network size by one, as per `Gossip::remove_peer()`.
+    RemovePeer,
+}
+
+/// A notable change in a message's progress, as returned by `Gossip::drain_events()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GossipEvent<D> {
+    /// A message was first learned, either from this node or from a peer.
+    NewMessage(D),
+    /// A message moved from state B to state C at the end of a round.
+    EnteredC(D),
+    /// A message moved to state D at the end of a round, so will no longer be pushed.
+    Terminated(D),
+}
+
+/// Where a held message originated, from this node's perspective.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Origin {
+    /// The message was started by this node via `new_message()`.
+    Local,
+    /// The message was first learned from a peer.
+    Remote,
+}
+
+impl Default for Origin {
+    fn default() -> Self {
+        Origin::Remote
+    }
+}
+
+/// A message held by a gossiper, along with its state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// The message itself, shared with rather than copied into any RPCs carrying it.  If
+    /// `compressed` is set, this is the compressed form.
+    #[serde(with = "::messages::shared_bytes")]
+    pub msg: Arc<[u8]>,
+    /// Whether `msg` is held compressed.
+    #[serde(default)]
+    pub compressed: bool,
+    /// The SHA3-256 hash of the uncompressed message if `compressed` is set, against which copies
+    /// received later are checked rather than decompressing `msg` each time.
+    #[serde(default)]
+    pub uncompressed_hash: Option<Digest256>,
+    /// Our state for the message.
+    pub state: MessageState,
+    /// Whether the message was started by this node or learned from a peer.
+    #[serde(default)]
+    pub origin: Origin,
+    /// The round during which we first learned of the message.
+    pub first_round: u64,
+    /// The latest round during which we first learned of the message, it was refreshed, or our
+    /// counter for it was incremented by the median rule applied at the round's end.
+    #[serde(default)]
+    pub last_changed_round: u64,
+    /// The number of times the message has been returned to state B by `Gossip::refresh()` after
+    /// leaving it.
+    #[serde(default)]
+    pub rehot_count: u8,
+}
+
+impl StoredMessage {
+    fn new(
+        msg: Arc<[u8]>,
+        state: MessageState,
+        origin: Origin,
+        first_round: u64,
+        compress: bool,
+    ) -> Result<Self, Error> {
+        let (msg, compressed, uncompressed_hash) = if compress {
+            let hash = hash_message(&msg);
+            (compression::compress(&msg)?.into(), true, Some(hash))
+        } else {
+            (msg, false, None)
+        };
+        Ok(StoredMessage {
+            msg,
+            compressed,
+            uncompressed_hash,
+            state,
+            origin,
+            first_round,
+            last_changed_round: first_round,
+            rehot_count: 0,
+        })
+    }
+
+    /// Returns the message itself, decompressing it if it's held compressed.
+    pub fn payload(&self) -> Result<Arc<[u8]>, Error> {
+        if self.compressed {
+            return compression::decompress(&self.msg).map(Arc::from);
+        }
+        Ok(self.msg.clone())
+    }
+
+    // Returns whether this holds `msg`.  Unless it's held compressed without a cached hash (e.g.
+    // restored from an older snapshot), this doesn't need to decompress it.
+    fn matches(&self, msg: &[u8]) -> bool {
+        if !self.compressed {
+            return *self.msg == *msg;
+        }
+        match self.uncompressed_hash {
+            Some(ref hash) => *hash == hash_message(msg),
+            None => payload(self).map_or(true, |held| *held == *msg),
+        }
+    }
+}
+
+/// A borrowed view of a held message, as returned by `Gossip::message_ref()` and
+/// `Gossip::iter_messages()`.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageRef<'a, D> {
+    /// The hash of the message.
+    pub digest: D,
+    /// The message and our state for it.
+    pub stored: &'a StoredMessage,
+}
+
+impl<'a, D> MessageRef<'a, D> {
+    /// The phase the message is currently in.
+    pub fn phase(&self) -> Phase {
+        self.stored.state.phase()
+    }
+
+    /// Our counter for the message as sent in RPCs, or `None` if its propagation is complete.
+    pub fn counter(&self) -> Option<u8> {
+        self.stored.state.our_counter()
+    }
+
+    /// The total number of rounds the message has spent in states B and C, or `None` if its
+    /// propagation is complete.
+    pub fn rounds(&self) -> Option<u8> {
+        self.stored.state.rounds()
+    }
+
+    /// Returns the message itself.  This only copies it if it's held compressed.
+    pub fn payload(&self) -> Result<Arc<[u8]>, Error> {
+        self.stored.payload()
+    }
+}
+
+/// A message to be pushed during a round, along with its hash and state.
+#[derive(Clone, Debug)]
+pub struct PushItem<D> {
+    /// The hash of the message.
+    pub digest: D,
+    /// Our counter for the message, as sent in the Push RPC.
+    pub counter: u8,
+    /// Our state for the message, having advanced to the new round.
+    pub state: MessageState,
+    /// The message itself.
+    pub msg: Arc<[u8]>,
+}
+
+/// Builder for a `Gossip` with non-default configuration.  The hash function is chosen via the
+/// type parameter `H`.
+#[derive(Clone, Debug)]
+pub struct GossipBuilder<H: Hasher = Sha3Hasher> {
+    total_peers: u64,
+    config: GossipConfig,
+    _hasher: PhantomData<H>,
+}
+
+impl<H: Hasher> GossipBuilder<H> {
+    /// Construct a new builder, initially yielding the same configuration as `Gossip::new()`.
+    pub fn new() -> Self {
+        GossipBuilder {
+            total_peers: 0,
+            config: GossipConfig::default(),
+            _hasher: PhantomData,
+        }
+    }
+
+    /// Set the number of peers in the network, excluding this node.  See
+    /// `Gossip::set_total_peers()`.
+    pub fn total_peers(mut self, total_peers: u64) -> Self {
+        self.total_peers = total_peers;
+        self
+    }
+
+    /// Set the minimum values of the thresholds derived from the network size.  See
+    /// `Gossip::set_min_rounds()`.
+    pub fn min_rounds(mut self, counter_max: u8, max_c_rounds: u8, max_rounds: u8) -> Self {
+        self.config.min_rounds = (counter_max, max_c_rounds, max_rounds);
+        self
+    }
+
+    /// Set the maximum size in bytes of a message.  See `Gossip::set_max_message_size()`.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.config.max_message_size = max_message_size;
+        self
+    }
+
+    /// Set the maximum number of peer counters recorded per message per round.  See
+    /// `Gossip::set_max_hits_per_round()`.
+    pub fn max_hits_per_round(mut self, max_hits_per_round: usize) -> Self {
+        self.config.max_hits_per_round = Some(max_hits_per_round);
+        self
+    }
+
+    /// Set the maximum number of messages pushed per round.  See
+    /// `Gossip::set_max_push_per_round()`.
+    pub fn max_push_per_round(mut self, max_push_per_round: usize) -> Self {
+        self.config.max_push_per_round = Some(max_push_per_round);
+        self
+    }
+
+    /// Set the maximum number of rounds a message is held for.  See
+    /// `Gossip::set_max_age_rounds()`.
+    pub fn max_age_rounds(mut self, max_age_rounds: u64) -> Self {
+        self.config.max_age_rounds = Some(max_age_rounds);
+        self
+    }
+
+    /// Set the maximum total size in bytes of all held messages.  See
+    /// `Gossip::set_max_total_bytes()`.
+    pub fn max_total_bytes(mut self, max_total_bytes: usize) -> Self {
+        self.config.max_total_bytes = Some(max_total_bytes);
+        self
+    }
+
+    /// Set the value to which peers' counters are clamped on receipt.  See
+    /// `Gossip::set_trusted_counter_cap()`.
+    pub fn trusted_counter_cap(mut self, trusted_counter_cap: u8) -> Self {
+        self.config.trusted_counter_cap = Some(trusted_counter_cap);
+        self
+    }
+
+    /// Set the rule by which peers' counters advance ours.  See `Gossip::set_median_rule()`.
+    pub fn median_rule(mut self, median_rule: MedianRule) -> Self {
+        self.config.median_rule = median_rule;
+        self
+    }
+
+    /// Set the number of times each message may be returned to state B by refreshing it.  See
+    /// `Gossip::set_max_rehot()`.
+    pub fn max_rehot(mut self, max_rehot: u8) -> Self {
+        self.config.max_rehot = Some(max_rehot);
+        self
+    }
+
+    /// Set the number of rounds for which pruned terminated messages are tombstoned.  See
+    /// `Gossip::set_tombstone_rounds()`.
+    pub fn tombstone_rounds(mut self, tombstone_rounds: u64) -> Self {
+        self.config.tombstone_rounds = Some(tombstone_rounds);
+        self
+    }
+
+    /// Set whether newly stored messages are held compressed.  See
+    /// `Gossip::set_compress_payloads()`.
+    #[cfg(feature = "compression")]
+    pub fn compress_payloads(mut self, compress_payloads: bool) -> Self {
+        self.config.compress_payloads = compress_payloads;
+        self
+    }
+
+    /// Set whether only signed messages are accepted.  See `Gossip::set_signed()`.
+    #[cfg(feature = "signed")]
+    pub fn signed(mut self, signed: bool) -> Self {
+        self.config.signed = signed;
+        self
+    }
+
+    /// Enable or disable recording of the hit histogram.  See
+    /// `Gossip::set_hit_histogram_enabled()`.
+    pub fn hit_histogram_enabled(mut self, enabled: bool) -> Self {
+        self.config.hit_histogram_enabled = enabled;
+        self
+    }
+
+    /// Construct the configured `Gossip`.
+    pub fn build(self) -> Gossip<H> {
+        let mut gossip = Gossip::with_config(self.config);
+        gossip.set_total_peers(self.total_peers);
+        gossip
+    }
+}
+
+impl<H: Hasher> Default for GossipBuilder<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A copy of the entire state of a gossiper's protocol handler.  With the "persistence" feature,
+/// this can be serialised, e.g. for crash recovery, and restored via `Gossip::from_snapshot()`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "persistence", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "persistence",
+           serde(bound(deserialize = "D: Ord + ::serde::Deserialize<'de>")))]
+pub struct GossipSnapshot<D> {
+    /// The held messages and their states, keyed by hash.
+    pub messages: BTreeMap<D, StoredMessage>,
+    /// The number of nodes in the network, including this one.
+    pub network_size: f64,
+    /// The peers registered by identity.
+    pub peer_ids: BTreeSet<Id>,
+    /// The tunable settings.  The round thresholds are recomputed from these and the network size
+    /// on restoring.
+    pub config: GossipConfig,
+    /// The hashes of tombstoned messages, mapped to the round in which each was pruned.
+    pub tombstones: BTreeMap<D, u64>,
+    /// All peers with which we communicated during the current round.
+    pub peers_in_this_round: BTreeSet<Id>,
+    /// The weights given to peers' counters during the current round, for those other than `1`.
+    pub peer_weights: BTreeMap<Id, u32>,
+    /// The statistics.
+    pub statistics: Statistics,
+    /// The hit histogram, empty unless enabled.
+    pub hit_histogram: Vec<u32>,
+}
+
+/// The tunable settings of a gossiper's protocol handler, as returned by `Gossip::config()`.
+/// `None` values mean the setting is disabled or unlimited, the same as for the corresponding
+/// setter.  The default is the configuration of `Gossip::new()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "persistence", derive(Serialize, Deserialize))]
+pub struct GossipConfig {
+    /// The thresholds `(counter_max, max_c_rounds, max_rounds)` if set via `Gossip::set_rounds()`,
+    /// or `None` if they're derived from the network size.
+    pub rounds_override: Option<(u8, u8, u8)>,
+    /// The minimum values of the thresholds derived from the network size.
+    pub min_rounds: (u8, u8, u8),
+    /// The maximum size in bytes of a message which will be accepted.
+    pub max_message_size: usize,
+    /// The maximum number of peer counters recorded per message per round.
+    pub max_hits_per_round: Option<usize>,
+    /// The maximum number of messages pushed per round.
+    pub max_push_per_round: Option<usize>,
+    /// The maximum number of rounds a message is held for before being evicted as expired.
+    pub max_age_rounds: Option<u64>,
+    /// The maximum total size in bytes of all held messages.
+    pub max_total_bytes: Option<usize>,
+    /// The value to which peers' counters are clamped on receipt.
+    pub trusted_counter_cap: Option<u8>,
+    /// The rule by which peers' counters advance ours.
+    pub median_rule: MedianRule,
+    /// The number of times each message may be returned to state B by refreshing it.
+    pub max_rehot: Option<u8>,
+    /// The number of rounds for which pruned terminated messages are tombstoned.
+    pub tombstone_rounds: Option<u64>,
+    /// Whether newly stored messages are held compressed.
+    pub compress_payloads: bool,
+    /// Whether only messages signed via `Gossip::new_signed_message()` are accepted.
+    pub signed: bool,
+    /// Whether the hit histogram is being recorded.
+    pub hit_histogram_enabled: bool,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            rounds_override: None,
+            min_rounds: (
+                Gossip::<Sha3Hasher>::MIN_HOT_ROUNDS,
+                Gossip::<Sha3Hasher>::MIN_COLD_ROUNDS,
+                Gossip::<Sha3Hasher>::MIN_TOTAL_ROUNDS,
+            ),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_hits_per_round: None,
+            max_push_per_round: None,
+            max_age_rounds: None,
+            max_total_bytes: None,
+            trusted_counter_cap: None,
+            median_rule: MedianRule::default(),
+            max_rehot: None,
+            tombstone_rounds: None,
+            compress_payloads: false,
+            signed: false,
+            hit_histogram_enabled: false,
+        }
+    }
+}
+
+/// Aggregate figures on the progress of a gossiper's held messages, as returned by
+/// `Gossip::stats()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GossipStats {
+    /// The number of messages in state B.
+    pub in_state_b: usize,
+    /// The number of messages in state C.
+    pub in_state_c: usize,
+    /// The number of messages in state D.
+    pub in_state_d: usize,
+    /// The number of rounds started so far.
+    pub rounds: u64,
+    /// The total size in bytes of all held messages.
+    pub total_bytes: u64,
+}
 
 /// Statistics on each gossiper.
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
 pub struct Statistics {
     /// Total rounds experienced (each push_tick is considered as one round).
     pub rounds: u64,
@@ -219,6 +2154,16 @@ pub struct Statistics {
     pub full_message_sent: u64,
     /// Total full message this gossiper received.
     pub full_message_received: u64,
+    /// Total bytes of messages this gossiper has pushed, saturating at `u64::MAX`.
+    #[serde(default)]
+    pub bytes_pushed: u64,
+    /// Total bytes of messages this gossiper has sent in Pull RPCs, saturating at `u64::MAX`.
+    #[serde(default)]
+    pub bytes_pulled: u64,
+    /// Total peers' counters ignored as the cap set via `Gossip::set_max_hits_per_round()` had
+    /// already been reached for that message and round.
+    #[serde(default)]
+    pub hits_dropped: u64,
 }
 
 impl Statistics {
@@ -230,6 +2175,9 @@ impl Statistics {
             empty_push_sent: u64::MAX,
             full_message_sent: u64::MAX,
             full_message_received: u64::MAX,
+            bytes_pushed: u64::MAX,
+            bytes_pulled: u64::MAX,
+            hits_dropped: u64::MAX,
         }
     }
 
@@ -240,6 +2188,9 @@ impl Statistics {
         self.empty_push_sent += other.empty_push_sent;
         self.full_message_sent += other.full_message_sent;
         self.full_message_received += other.full_message_received;
+        self.bytes_pushed = self.bytes_pushed.saturating_add(other.bytes_pushed);
+        self.bytes_pulled = self.bytes_pulled.saturating_add(other.bytes_pulled);
+        self.hits_dropped += other.hits_dropped;
     }
 
     /// Update self with the min of self and other
@@ -250,6 +2201,9 @@ impl Statistics {
         self.full_message_sent = cmp::min(self.full_message_sent, other.full_message_sent);
         self.full_message_received =
             cmp::min(self.full_message_received, other.full_message_received);
+        self.bytes_pushed = cmp::min(self.bytes_pushed, other.bytes_pushed);
+        self.bytes_pulled = cmp::min(self.bytes_pulled, other.bytes_pulled);
+        self.hits_dropped = cmp::min(self.hits_dropped, other.hits_dropped);
     }
 
     /// Update self with the max of self and other
@@ -260,6 +2214,9 @@ impl Statistics {
         self.full_message_sent = cmp::max(self.full_message_sent, other.full_message_sent);
         self.full_message_received =
             cmp::max(self.full_message_received, other.full_message_received);
+        self.bytes_pushed = cmp::max(self.bytes_pushed, other.bytes_pushed);
+        self.bytes_pulled = cmp::max(self.bytes_pulled, other.bytes_pulled);
+        self.hits_dropped = cmp::max(self.hits_dropped, other.hits_dropped);
     }
 }
 
@@ -268,12 +2225,2293 @@ impl Debug for Statistics {
         write!(
             formatter,
             "rounds: {},  empty pull sent: {},  empty push sent: {}, full messages sent: {},  \n
-             full messages received: {}",
+             full messages received: {},  bytes pushed: {},  bytes pulled: {},  \
+             hits dropped: {}",
             self.rounds,
             self.empty_pull_sent,
             self.empty_push_sent,
             self.full_message_sent,
-            self.full_message_received
+            self.full_message_received,
+            self.bytes_pushed,
+            self.bytes_pulled,
+            self.hits_dropped
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::PUBLIC_KEY_LENGTH;
+    use hasher::{Digest256, MessageHasher, hash_message};
+    #[cfg(feature = "persistence")]
+    use maidsafe_utilities::serialisation;
+    use rand::{Rng, SeedableRng, StdRng};
+
+    fn peer_id(index: usize) -> Id {
+        let mut id = [0; PUBLIC_KEY_LENGTH];
+        id[0] = (index >> 8) as u8;
+        id[1] = index as u8;
+        Id(id)
+    }
+
+    fn peer_counter_count(gossip: &Gossip, digest: &Digest256) -> usize {
+        gossip.peer_counters(digest).map_or(0, BTreeMap::len)
+    }
+
+    // Restores a copy of `gossip` from its snapshot, which is first serialised and deserialised if
+    // the "persistence" feature is enabled.
+    fn round_trip<H: Hasher>(gossip: &Gossip<H>) -> Gossip<H> {
+        let snapshot = gossip.snapshot();
+        #[cfg(feature = "persistence")]
+        let serialised = unwrap!(serialisation::serialise(&snapshot));
+        #[cfg(feature = "persistence")]
+        let snapshot = unwrap!(serialisation::deserialise(&serialised));
+        unwrap!(Gossip::from_snapshot(snapshot))
+    }
+
+    fn gossip_with_network_size(network_size: u64) -> Gossip {
+        let mut gossip = Gossip::new();
+        for _ in 1..network_size {
+            gossip.add_peer();
+        }
+        gossip
+    }
+
+    fn rounds(gossip: &Gossip) -> (u8, u8, u8) {
+        (gossip.counter_max(), gossip.max_c_rounds(), gossip.max_rounds())
+    }
+
+    #[test]
+    fn rounds_for_tiny_networks() {
+        assert_eq!(rounds(&gossip_with_network_size(1)), (1, 1, 1));
+        assert_eq!(rounds(&gossip_with_network_size(2)), (1, 1, 1));
+        assert_eq!(rounds(&gossip_with_network_size(3)), (1, 1, 2));
+        assert_eq!(rounds(&gossip_with_network_size(10)), (1, 1, 3));
+    }
+
+    #[test]
+    fn set_total_peers_matches_add_peer() {
+        for total_peers in &[0, 1, 2, 9, 100, 10_000] {
+            let mut gossip = Gossip::new();
+            gossip.set_total_peers(*total_peers);
+            let expected = gossip_with_network_size(total_peers + 1);
+            assert_eq!(gossip.network_size, expected.network_size);
+            assert_eq!(rounds(&gossip), rounds(&expected));
+        }
+    }
+
+    #[test]
+    fn huge_networks() {
+        let mut largest = Gossip::new();
+        largest.set_total_peers(MAX_TOTAL_PEERS);
+        assert_eq!(rounds(&largest), (4, 4, 37));
+        let network_size = largest.network_size;
+        largest.add_peer();
+        assert_eq!(largest.network_size, network_size);
+        assert_eq!(rounds(&largest), (4, 4, 37));
+
+        let mut beyond = Gossip::new();
+        beyond.set_total_peers(u64::MAX);
+        assert_eq!(beyond.network_size, network_size);
+        assert_eq!(rounds(&beyond), rounds(&largest));
+        beyond.remove_peer();
+        assert!(beyond.network_size < network_size);
+    }
+
+    #[test]
+    fn message_ref() {
+        let mut gossip = gossip_with_network_size(21);
+        assert!(gossip.message_ref(&hash_message(b"message")).is_none());
+        assert_eq!(gossip.iter_messages().count(), 0);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        let rpc = GossipRpc::Push {
+            msg: b"remote".to_vec().into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+
+        let message = unwrap!(gossip.message_ref(&digest));
+        assert_eq!(message.digest, digest);
+        assert_eq!(message.phase(), Phase::B);
+        assert_eq!(message.counter(), Some(1));
+        assert_eq!(message.rounds(), Some(0));
+        let payload = unwrap!(message.payload());
+        assert!(Arc::ptr_eq(&payload, &unwrap!(gossip.message(&digest)).msg));
+
+        let mut expected = vec![(digest, Phase::B), (hash_message(b"remote"), Phase::C)];
+        expected.sort();
+        let phases: Vec<_> = gossip
+            .iter_messages()
+            .map(|message| (message.digest, message.phase()))
+            .collect();
+        assert_eq!(phases, expected);
+    }
+
+    #[test]
+    fn from_messages() {
+        let items = vec![
+            (0, b"zero".to_vec()),
+            (2, b"two".to_vec()),
+            (3, b"three".to_vec()),
+            (200, b"two hundred".to_vec()),
+            (1, b"two".to_vec()),
+        ];
+        let mut gossip: Gossip = Gossip::from_messages(items);
+        gossip.set_total_peers(2_000);
+        assert_eq!(gossip.counter_max(), 3);
+        assert_eq!(gossip.message_count(), 4);
+        let mut expected = vec![
+            (hash_message(b"zero"), 1),
+            (hash_message(b"two"), 2),
+            (hash_message(b"three"), u8::MAX),
+            (hash_message(b"two hundred"), u8::MAX),
+        ];
+        expected.sort();
+        let pushed: Vec<_> = gossip
+            .next_round_detailed()
+            .into_iter()
+            .map(|item| (item.digest, item.counter))
+            .collect();
+        assert_eq!(pushed, expected);
+
+        // Invalid messages are ignored.
+        let invalid = vec![
+            (1, vec![0; DEFAULT_MAX_MESSAGE_SIZE + 1]),
+            (1, vec![]),
+            (1, b"valid".to_vec()),
+        ];
+        let gossip: Gossip = Gossip::from_messages(invalid.clone());
+        assert_eq!(gossip.message_count(), 1);
+        // Whereas `try_from_messages()` reports them.
+        match Gossip::<Sha3Hasher>::try_from_messages(invalid) {
+            Err(Error::TooLarge) => (),
+            result => panic!("Unexpected result {:?}", result.map(|gossip| gossip.message_count())),
+        }
+        match Gossip::<Sha3Hasher>::try_from_messages(vec![(1, vec![])]) {
+            Err(Error::Empty) => (),
+            result => panic!("Unexpected result {:?}", result.map(|gossip| gossip.message_count())),
+        }
+        let valid = vec![(1, b"one".to_vec()), (1, b"one".to_vec())];
+        let gossip: Gossip = unwrap!(Gossip::try_from_messages(valid));
+        assert_eq!(gossip.message_count(), 1);
+    }
+
+    #[test]
+    fn with_peers_matches_add_peer() {
+        for total_peers in &[0, 1, 2, 9, 20, 1_000] {
+            let gossip: Gossip = Gossip::with_peers(*total_peers);
+            let expected = gossip_with_network_size(total_peers + 1);
+            assert_eq!(gossip.network_size, expected.network_size);
+            assert_eq!(rounds(&gossip), rounds(&expected));
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let gossip: Gossip = GossipBuilder::new()
+            .total_peers(20)
+            .max_message_size(10)
+            .max_hits_per_round(3)
+            .max_push_per_round(2)
+            .max_age_rounds(5)
+            .max_total_bytes(100)
+            .trusted_counter_cap(1)
+            .median_rule(MedianRule::Median)
+            .hit_histogram_enabled(true)
+            .build();
+        assert_eq!(rounds(&gossip), rounds(&Gossip::with_peers(20)));
+        let config = gossip.config();
+        assert_eq!(config.max_message_size, 10);
+        assert_eq!(config.max_hits_per_round, Some(3));
+        assert_eq!(config.max_push_per_round, Some(2));
+        assert_eq!(config.max_age_rounds, Some(5));
+        assert_eq!(config.max_total_bytes, Some(100));
+        assert_eq!(config.trusted_counter_cap, Some(1));
+        assert_eq!(config.median_rule, MedianRule::Median);
+        assert!(config.hit_histogram_enabled);
+
+        let default: Gossip = GossipBuilder::new().build();
+        assert_eq!(rounds(&default), rounds(&Gossip::new()));
+        assert_eq!(default.config(), &GossipConfig::default());
+        assert_eq!(default.config(), Gossip::<Sha3Hasher>::new().config());
+        assert_eq!(default.config().max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(default.config().median_rule, MedianRule::Majority);
+        assert!(!default.config().hit_histogram_enabled);
+
+        // Thresholds given as `0` are raised to `1`, as per the setters.
+        let gossip: Gossip = GossipBuilder::new().min_rounds(0, 0, 0).build();
+        assert_eq!(gossip.config().min_rounds, (1, 1, 1));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn builder_compress_payloads() {
+        let mut gossip: Gossip = GossipBuilder::new().compress_payloads(true).build();
+        assert!(gossip.config().compress_payloads);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        assert!(unwrap!(gossip.message(&digest)).compressed);
+    }
+
+    #[test]
+    fn config() {
+        let mut gossip: Gossip = GossipBuilder::new()
+            .total_peers(20)
+            .min_rounds(2, 2, 5)
+            .max_push_per_round(3)
+            .trusted_counter_cap(1)
+            .median_rule(MedianRule::Median)
+            .tombstone_rounds(4)
+            .build();
+        gossip.set_hit_histogram_enabled(true);
+        let config = gossip.config();
+        assert_eq!(config.rounds_override, None);
+        assert_eq!(config.min_rounds, (2, 2, 5));
+        assert_eq!(config.max_message_size, DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(config.max_push_per_round, Some(3));
+        assert_eq!(config.max_total_bytes, None);
+        assert_eq!(config.median_rule, MedianRule::Median);
+        assert!(config.hit_histogram_enabled);
+
+        gossip.set_rounds(3, 3, 6);
+        assert_eq!(gossip.config().rounds_override, Some((3, 3, 6)));
+        let restored: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+        assert_eq!(restored.config(), gossip.config());
+        assert_eq!(rounds(&restored), (3, 3, 6));
+        assert_eq!(round_trip(&gossip).config(), gossip.config());
+    }
+
+    #[test]
+    fn random_ops() {
+        check_random_ops(false);
+        // Only ever receiving Pulls, no Push responses are due, and messages spread solely via the
+        // pull lists.
+        check_random_ops(true);
+    }
+
+    // Applies random sequences of operations, receiving only Pull RPCs if `pull_only` is set.
+    fn check_random_ops(pull_only: bool) {
+        for seed in 0..10 {
+            let mut rng = StdRng::from_seed(&[seed][..]);
+            let mut gossip = gossip_with_network_size(21);
+            for _ in 0..300 {
+                let msg = vec![rng.gen_range(0, 10)];
+                let op = match rng.gen_range(0, 11) {
+                    0 => GossipOp::NewMessage(msg),
+                    1 | 2 | 3 | 4 | 5 => {
+                        let counter = rng.gen_range(0, gossip.counter_max() + 2);
+                        let msg = if rng.gen_weighted_bool(4) { vec![] } else { msg };
+                        let rpc = if !pull_only && rng.gen() {
+                            GossipRpc::Push {
+                                msg: msg.into(),
+                                counter,
+                            }
+                        } else {
+                            GossipRpc::Pull {
+                                msg: msg.into(),
+                                counter,
+                            }
+                        };
+                        GossipOp::Receive(peer_id(rng.gen_range(0, 30)), rpc)
+                    }
+                    6 | 7 => GossipOp::NextRound,
+                    8 => GossipOp::PullList,
+                    9 => GossipOp::AddPeer,
+                    _ => GossipOp::RemovePeer,
+                };
+                let pull_list_len = gossip.pull_list_len();
+                let next_round = match op {
+                    GossipOp::NextRound => true,
+                    GossipOp::PullList => {
+                        // This is a query only, so leaves the entire state unchanged.
+                        let before = format!("{:?}", gossip.snapshot());
+                        let pulls = unwrap!(gossip.apply_op(op));
+                        assert_eq!(pulls.len(), pull_list_len);
+                        assert_eq!(format!("{:?}", gossip.snapshot()), before);
+                        continue;
+                    }
+                    _ => false,
+                };
+                // Invalid operations, e.g. an empty message with a non-zero counter, are rejected
+                // without affecting the state.
+                let responses = gossip.apply_op(op);
+                if pull_only && !next_round {
+                    assert!(responses.map_or(true, |responses| responses.is_empty()));
+                }
+                gossip.verify_invariants();
+                if !next_round {
+                    continue;
+                }
+
+                // At the end of each round, every message which hasn't terminated is strictly
+                // within the thresholds which then applied.
+                for stored in gossip.messages.values() {
+                    match stored.state {
+                        MessageState::B {
+                            round,
+                            our_counter,
+                            ..
+                        } => {
+                            assert!(our_counter < gossip.counter_max());
+                            assert!(round < gossip.max_rounds());
+                        }
+                        MessageState::C {
+                            rounds_in_state_b,
+                            round,
+                        } => {
+                            assert!(round < gossip.max_c_rounds());
+                            assert!(rounds_in_state_b + round < gossip.max_rounds());
+                        }
+                        MessageState::D => (),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn set_rounds() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_rounds(3, 0, 7);
+        assert_eq!(rounds(&gossip), (3, 1, 7));
+        gossip.add_peer();
+        gossip.set_total_peers(1_000);
+        gossip.remove_peer();
+        assert_eq!(rounds(&gossip), (3, 1, 7));
+        gossip.clear_rounds_override();
+        assert_eq!(rounds(&gossip), rounds(&gossip_with_network_size(1_000)));
+    }
+
+    #[test]
+    fn peer_ids() {
+        let mut gossip: Gossip = Gossip::new();
+        for index in 0..20 {
+            assert!(gossip.add_peer_id(peer_id(index)));
+        }
+        assert!(!gossip.add_peer_id(peer_id(0)));
+        assert_eq!(rounds(&gossip), rounds(&gossip_with_network_size(21)));
+        assert!(gossip.remove_peer_id(&peer_id(0)));
+        assert!(!gossip.remove_peer_id(&peer_id(0)));
+        assert_eq!(gossip.network_size, 20.0);
+        let expected: Vec<Id> = (1..20).map(peer_id).collect();
+        assert_eq!(gossip.peer_ids().cloned().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn rounds_are_monotonic() {
+        let mut gossip: Gossip = Gossip::new();
+        let mut previous = rounds(&gossip);
+        for total_peers in 1..1_000_001 {
+            gossip.set_total_peers(total_peers);
+            let current = rounds(&gossip);
+            assert!(current.0 >= previous.0, "counter_max fell at {}", total_peers);
+            assert!(current.1 >= previous.1, "max_c_rounds fell at {}", total_peers);
+            assert!(current.2 >= previous.2, "max_rounds fell at {}", total_peers);
+            // Termination can't precede state C.
+            assert!(current.2 >= current.1);
+            previous = current;
+        }
+    }
+
+    #[test]
+    fn has_message() {
+        let mut gossip: Gossip = Gossip::new();
+        let msg = b"message".to_vec();
+        let digest = unwrap!(gossip.new_message(msg.clone()));
+        assert_eq!(digest, hash_message(&msg));
+        assert!(gossip.has_message(&digest));
+        assert!(gossip.has_message_bytes(&msg));
+        assert!(!gossip.has_message(&hash_message(b"other")));
+        assert!(!gossip.has_message_bytes(b"other"));
+    }
+
+    #[test]
+    fn message() {
+        let mut gossip: Gossip = Gossip::new();
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        let stored = unwrap!(gossip.message(&digest));
+        assert_eq!(&stored.msg[..], b"message");
+        assert_eq!(stored.state.phase(), Phase::B);
+        match gossip.message(&hash_message(b"other")) {
+            Err(Error::UnknownDigest) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn peer_counters() {
+        let mut gossip = gossip_with_network_size(21);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        assert_eq!(gossip.peer_counters(&digest), Some(&BTreeMap::new()));
+        for index in 0..2 {
+            let rpc = GossipRpc::Push {
+                msg: b"message".to_vec().into(),
+                counter: index as u8,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index), rpc));
+        }
+        let expected = (0..2).map(|index| (peer_id(index), index as u8)).collect();
+        assert_eq!(gossip.peer_counters(&digest), Some(&expected));
+        assert_eq!(gossip.peer_counters(&hash_message(b"other")), None);
+
+        let _ = gossip.next_round();
+        assert_eq!(gossip.peer_counters(&digest), Some(&BTreeMap::new()));
+    }
+
+    #[test]
+    fn for_each_message() {
+        let mut gossip: Gossip = Gossip::new();
+        for index in 0..3 {
+            let _ = unwrap!(gossip.new_message(vec![index]));
+        }
+        let mut visited = vec![];
+        gossip.for_each_message(|digest, msg| {
+            assert_eq!(*digest, hash_message(msg));
+            visited.push(msg.to_vec());
+        });
+        visited.sort();
+        assert_eq!(visited, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn message_phase() {
+        let mut gossip = gossip_with_network_size(2);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        assert_eq!(gossip.message_phase(&digest), Some(Phase::B));
+        let _ = gossip.next_round();
+        assert_eq!(gossip.message_phase(&digest), Some(Phase::D));
+        assert_eq!(gossip.message_phase(&hash_message(b"other")), None);
+    }
+
+    #[test]
+    fn messages_in_phase() {
+        let mut gossip = gossip_with_network_size(21);
+        let local = unwrap!(gossip.new_message(b"local".to_vec()));
+        let rpc = GossipRpc::Push {
+            msg: b"remote".to_vec().into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        assert_eq!(gossip.messages_in_phase(Phase::B), vec![local]);
+        assert_eq!(gossip.messages_in_phase(Phase::C), vec![hash_message(b"remote")]);
+        assert!(gossip.messages_in_phase(Phase::D).is_empty());
+        for _ in 0..gossip.max_rounds() {
+            let _ = gossip.next_round();
+        }
+        let mut terminated = vec![local, hash_message(b"remote")];
+        terminated.sort();
+        assert_eq!(gossip.messages_in_phase(Phase::D), terminated);
+    }
+
+    #[test]
+    fn digest_set() {
+        let mut ours = gossip_with_network_size(21);
+        let mut theirs = gossip_with_network_size(21);
+        let shared = unwrap!(ours.new_message(b"shared".to_vec()));
+        assert_eq!(unwrap!(theirs.new_message(b"shared".to_vec())), shared);
+        let only_ours = unwrap!(ours.new_message(b"ours".to_vec()));
+        let only_theirs = unwrap!(theirs.new_message(b"theirs".to_vec()));
+
+        let our_set = ours.digest_set();
+        let their_set = theirs.digest_set();
+        assert_eq!(our_set.len(), 2);
+        assert_eq!(our_set.intersection(&their_set).collect::<Vec<_>>(), vec![&shared]);
+        assert_eq!(our_set.difference(&their_set).collect::<Vec<_>>(), vec![&only_ours]);
+        assert_eq!(their_set.difference(&our_set).collect::<Vec<_>>(), vec![&only_theirs]);
+    }
+
+    #[test]
+    fn missing() {
+        let mut gossip = gossip_with_network_size(2);
+        gossip.set_tombstone_rounds(Some(5));
+        let pruned = unwrap!(gossip.new_message(b"pruned".to_vec()));
+        let _ = gossip.next_round();
+        assert_eq!(gossip.prune_terminated(), 1);
+        let held = unwrap!(gossip.new_message(b"held".to_vec()));
+        let absent = [hash_message(b"first"), hash_message(b"second")];
+
+        let wanted = [absent[1], held, pruned, absent[0]];
+        assert_eq!(gossip.missing(&wanted), vec![absent[1], absent[0]]);
+        assert!(gossip.missing(&[held]).is_empty());
+        assert!(gossip.missing(&[]).is_empty());
+    }
+
+    #[test]
+    fn messages_by_progress() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_rounds(2, 2, 4);
+        assert!(gossip.messages_by_progress().is_empty());
+        let terminated = unwrap!(gossip.new_message(b"terminated".to_vec()));
+        for _ in 0..4 {
+            let _ = gossip.next_round();
+        }
+        let halfway = unwrap!(gossip.new_message(b"halfway".to_vec()));
+        let _ = gossip.next_round();
+        let _ = gossip.next_round();
+        let first = unwrap!(gossip.new_message(b"first".to_vec()));
+        let second = unwrap!(gossip.new_message(b"second".to_vec()));
+
+        let (first, second) = (cmp::min(first, second), cmp::max(first, second));
+        let expected = vec![(first, 0.0), (second, 0.0), (halfway, 0.5), (terminated, 1.0)];
+        assert_eq!(gossip.messages_by_progress(), expected);
+    }
+
+    #[test]
+    fn terminated() {
+        let mut gossip = gossip_with_network_size(10);
+        assert!(gossip.all_terminated());
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        assert!(!gossip.is_terminated(&digest));
+        assert!(!gossip.all_terminated());
+        for _ in 0..gossip.max_rounds() {
+            let _ = gossip.next_round();
+        }
+        assert!(gossip.is_terminated(&digest));
+        assert!(gossip.all_terminated());
+        assert!(!gossip.is_terminated(&hash_message(b"other")));
+    }
+
+    #[test]
+    fn prune_terminated() {
+        let mut gossip = gossip_with_network_size(2);
+        let old_digest = unwrap!(gossip.new_message(b"old".to_vec()));
+        let _ = gossip.next_round();
+        let new_digest = unwrap!(gossip.new_message(b"new".to_vec()));
+        assert_eq!(gossip.prune_terminated(), 1);
+        assert!(!gossip.has_message(&old_digest));
+        assert!(gossip.has_message(&new_digest));
+        assert_eq!(gossip.prune_terminated(), 0);
+    }
+
+    #[test]
+    fn tombstones() {
+        let mut gossip = gossip_with_network_size(2);
+        gossip.set_tombstone_rounds(Some(2));
+        let msg: Arc<[u8]> = b"message".to_vec().into();
+        let digest = unwrap!(gossip.new_message(msg.to_vec()));
+        let _ = gossip.next_round();
+        assert_eq!(gossip.prune_terminated(), 1);
+        assert!(gossip.is_tombstoned(&digest));
+
+        // While tombstoned, a copy from a peer is ignored, though it's still answered.
+        let rpc = GossipRpc::Push {
+            msg: msg.clone(),
+            counter: 1,
+        };
+        assert_eq!(unwrap!(gossip.receive(peer_id(0), rpc.clone())).len(), 1);
+        assert!(!gossip.has_message(&digest));
+
+        // The tombstone survives a snapshot, but expires after the configured number of rounds.
+        let restored: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+        assert!(restored.is_tombstoned(&digest));
+        let _ = gossip.next_round();
+        let _ = gossip.next_round();
+        assert!(gossip.is_tombstoned(&digest));
+        let _ = gossip.next_round();
+        assert!(!gossip.is_tombstoned(&digest));
+        let _ = unwrap!(gossip.receive(peer_id(1), rpc));
+        assert!(gossip.has_message(&digest));
+    }
+
+    #[test]
+    fn new_message_respects_tombstones() {
+        let mut gossip = gossip_with_network_size(2);
+        gossip.set_tombstone_rounds(Some(5));
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        let _ = gossip.next_round();
+        assert_eq!(gossip.prune_terminated(), 1);
+        let _ = gossip.drain_events();
+
+        assert_eq!(unwrap!(gossip.new_message(b"message".to_vec())), digest);
+        assert_eq!(unwrap!(gossip.new_messages(vec![b"message".to_vec()])), vec![digest]);
+        assert!(!gossip.has_message(&digest));
+        assert!(gossip.is_tombstoned(&digest));
+        assert!(gossip.drain_events().is_empty());
+
+        assert_eq!(unwrap!(gossip.new_message_forced(b"message".to_vec())), digest);
+        assert!(!gossip.is_tombstoned(&digest));
+        assert_eq!(gossip.message_phase(&digest), Some(Phase::B));
+        assert_eq!(gossip.drain_events(), vec![GossipEvent::NewMessage(digest)]);
+    }
+
+    #[test]
+    fn hits_per_round_are_bounded() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_rounds(5, 5, 10);
+        let msg: Arc<[u8]> = b"message".to_vec().into();
+        let digest = unwrap!(gossip.new_message(msg.to_vec()));
+        for index in 0..5000 {
+            let rpc = GossipRpc::Push {
+                msg: msg.clone(),
+                counter: 1,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index), rpc));
+        }
+        // By default, every peer's counter is recorded.
+        assert_eq!(peer_counter_count(&gossip, &digest), 5000);
+        assert_eq!(gossip.statistics().hits_dropped, 0);
+
+        gossip.set_max_hits_per_round(Some(3));
+        let _ = gossip.next_round();
+        for index in 0..5000 {
+            let rpc = GossipRpc::Pull {
+                msg: msg.clone(),
+                counter: 2,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index), rpc));
+        }
+        assert_eq!(peer_counter_count(&gossip, &digest), 3);
+        assert_eq!(gossip.statistics().hits_dropped, 4997);
+
+        // The ignored peers mustn't be taken to be in state A either, otherwise they'd outvote
+        // those recorded.
+        let _ = gossip.next_round();
+        assert_eq!(gossip.messages[&digest].state.our_counter(), Some(3));
+        assert_eq!(peer_counter_count(&gossip, &digest), 0);
+    }
+
+    #[test]
+    fn remove_message() {
+        let mut gossip = gossip_with_network_size(21);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        assert!(gossip.remove_message(&digest));
+        assert!(!gossip.remove_message(&digest));
+        assert!(!gossip.has_message(&digest));
+
+        // Both the Push and Pull lists should now only contain the empty RPC.
+        let is_empty_rpc = |rpcs: &[GossipRpc]| match *rpcs {
+            [GossipRpc::Push { ref msg, counter }] |
+            [GossipRpc::Pull { ref msg, counter }] => msg.is_empty() && counter == 0,
+            _ => false,
+        };
+        assert!(is_empty_rpc(&gossip.next_round()));
+        let push = GossipRpc::Push {
+            msg: vec![].into(),
+            counter: 0,
+        };
+        assert!(is_empty_rpc(&unwrap!(gossip.receive(peer_id(0), push))));
+    }
+
+    #[test]
+    fn clear_and_reset() {
+        let mut gossip = gossip_with_network_size(21);
+        let _ = unwrap!(gossip.new_message(b"message".to_vec()));
+        let _ = gossip.next_round();
+        let before = rounds(&gossip);
+
+        gossip.clear();
+        assert!(gossip.is_empty());
+        assert_eq!(gossip.statistics().rounds, 0);
+        assert_eq!(gossip.network_size, 21.0);
+        assert_eq!(rounds(&gossip), before);
+
+        let _ = unwrap!(gossip.new_message(b"message".to_vec()));
+        gossip.reset();
+        assert!(gossip.is_empty());
+        assert_eq!(gossip.network_size, 1.0);
+        assert_eq!(rounds(&gossip), (1, 1, 1));
+    }
+
+    #[test]
+    fn reject_invalid_messages() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_max_message_size(4);
+        match gossip.new_message(vec![]) {
+            Err(Error::Empty) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match gossip.new_message(vec![0; 5]) {
+            Err(Error::TooLarge) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let _ = unwrap!(gossip.new_message(vec![0; 4]));
+
+        let push = GossipRpc::Push {
+            msg: vec![].into(),
+            counter: 1,
+        };
+        match gossip.receive(peer_id(0), push) {
+            Err(Error::Empty) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let pull = GossipRpc::Pull {
+            msg: vec![1; 5].into(),
+            counter: 1,
+        };
+        match gossip.receive(peer_id(0), pull) {
+            Err(Error::TooLarge) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(gossip.message_count(), 1);
+        assert!(gossip.peers_in_this_round.is_empty());
+    }
+
+    #[test]
+    fn custom_hasher() {
+        // Identifies messages by their first byte only.
+        struct FirstByteHasher;
+
+        impl Hasher for FirstByteHasher {
+            type Digest = [u8; 1];
+
+            fn hash(data: &[u8]) -> [u8; 1] {
+                [data[0]]
+            }
+        }
+
+        let mut gossip = Gossip::<FirstByteHasher>::new();
+        assert_eq!(unwrap!(gossip.new_message(b"message".to_vec())), [b'm']);
+        assert!(gossip.has_message(&[b'm']));
+        assert!(gossip.has_message_bytes(b"more"));
+        assert!(!gossip.has_message_bytes(b"other"));
+    }
+
+    #[test]
+    fn digest_collision() {
+        // Identifies messages by their first byte only.
+        struct FirstByteHasher;
+
+        impl Hasher for FirstByteHasher {
+            type Digest = [u8; 1];
+
+            fn hash(data: &[u8]) -> [u8; 1] {
+                [data[0]]
+            }
+        }
+
+        let mut gossip = Gossip::<FirstByteHasher>::new();
+        gossip.set_total_peers(20);
+        let _ = unwrap!(gossip.new_message(b"message".to_vec()));
+        // The same message again isn't a collision.
+        let _ = unwrap!(gossip.new_message(b"message".to_vec()));
+        match gossip.new_message(b"more".to_vec()) {
+            Err(Error::DigestCollision) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        let push = |msg: &[u8]| {
+            GossipRpc::Push {
+                msg: msg.to_vec().into(),
+                counter: 1,
+            }
+        };
+        match gossip.receive(peer_id(0), push(b"more")) {
+            Err(Error::DigestCollision) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(gossip.peers_in_this_round.is_empty());
+        match gossip.receive_batch(peer_id(0), vec![push(b"other"), push(b"opposite")]) {
+            Err(Error::DigestCollision) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(!gossip.has_message(&[b'o']));
+
+        // The message originally held is untouched.
+        assert_eq!(&*unwrap!(unwrap!(gossip.message(&[b'm'])).payload()), b"message");
+        let _ = unwrap!(gossip.receive(peer_id(0), push(b"message")));
+        assert_eq!(gossip.peer_counters(&[b'm']).map(BTreeMap::len), Some(1));
+    }
+
+    #[cfg(feature = "persistence")]
+    #[test]
+    fn serialisation() {
+        let mut gossip = gossip_with_network_size(21);
+        for index in 0..10 {
+            let _ = unwrap!(gossip.new_message(vec![index]));
+        }
+        let _ = gossip.next_round();
+        for index in 5..15 {
+            let rpc = GossipRpc::Push {
+                msg: vec![index].into(),
+                counter: index % 3,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index as usize), rpc));
+        }
+
+        let serialised = unwrap!(serialisation::serialise(&gossip.snapshot()));
+        let snapshot = unwrap!(serialisation::deserialise(&serialised));
+        let mut restored: Gossip = unwrap!(Gossip::from_snapshot(snapshot));
+        assert_eq!(format!("{:?}", restored), format!("{:?}", gossip));
+        for _ in 0..gossip.max_rounds() {
+            assert_eq!(restored.next_round(), gossip.next_round());
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut gossip = gossip_with_network_size(21);
+        for index in 0..10 {
+            let _ = unwrap!(gossip.new_message(vec![index]));
+        }
+        let _ = gossip.next_round();
+        for index in 5..15 {
+            let rpc = GossipRpc::Pull {
+                msg: vec![index].into(),
+                counter: index % 3,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index as usize), rpc));
+        }
+
+        let mut restored: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+        assert_eq!(format!("{:?}", restored), format!("{:?}", gossip));
+        for _ in 0..gossip.max_rounds() {
+            assert_eq!(restored.next_round(), gossip.next_round());
+        }
+    }
+
+    #[test]
+    fn restore_mid_round() {
+        let mut gossip = gossip_with_network_size(21);
+        let supported = unwrap!(gossip.new_message(b"supported".to_vec()));
+        let contested = unwrap!(gossip.new_message(b"contested".to_vec()));
+        // Only the weight given to the peer sending `supported` lets it outvote the two others.
+        let rpc = GossipRpc::Push {
+            msg: b"supported".to_vec().into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive_weighted(peer_id(0), rpc, 3));
+        let rpc = GossipRpc::Push {
+            msg: b"contested".to_vec().into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(1), rpc));
+        let empty_push = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(2), empty_push));
+
+        // Restoring before the round ends, via either route, reaches the same median decisions.
+        let from_snapshot: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+        for mut restored in vec![from_snapshot, round_trip(&gossip)] {
+            let _ = restored.next_round_detailed();
+            assert_eq!(restored.last_round_median_bumps(), &[supported]);
+        }
+        let _ = gossip.next_round_detailed();
+        assert_eq!(gossip.last_round_median_bumps(), &[supported]);
+        assert_eq!(unwrap!(gossip.message_ref(&contested)).counter(), Some(1));
+    }
+
+    #[test]
+    fn merge() {
+        let mut lhs = gossip_with_network_size(21);
+        let mut rhs = gossip_with_network_size(21);
+        let lhs_only = unwrap!(lhs.new_message(b"lhs".to_vec()));
+        let rhs_only = unwrap!(rhs.new_message(b"rhs".to_vec()));
+        let shared = unwrap!(lhs.new_message(b"shared".to_vec()));
+        let _ = rhs.next_round();
+        let _ = unwrap!(rhs.new_message(b"shared".to_vec()));
+
+        // Disjoint messages are copied across.
+        unwrap!(lhs.merge(&rhs));
+        assert_eq!(lhs.message_count(), 3);
+        assert!(lhs.has_message(&lhs_only));
+        assert!(lhs.has_message(&rhs_only));
+        match lhs.messages[&rhs_only].state {
+            MessageState::B { round, .. } => assert_eq!(round, 1),
+            ref state => panic!("Unexpected state: {:?}", state),
+        }
+
+        // Shared messages keep the state furthest through propagation.
+        for _ in 0..lhs.max_rounds() {
+            let _ = lhs.next_round();
+        }
+        assert_eq!(lhs.message_phase(&shared), Some(Phase::D));
+        assert_eq!(rhs.message_phase(&shared), Some(Phase::B));
+        unwrap!(rhs.merge(&lhs));
+        assert_eq!(rhs.message_phase(&shared), Some(Phase::D));
+        assert_eq!(rhs.message_phase(&lhs_only), Some(Phase::D));
+        assert_eq!(rhs.message_count(), 3);
+
+        // Merged messages are subject to the same limits as those received.
+        let mut limited = gossip_with_network_size(21);
+        limited.set_max_total_bytes(Some(6));
+        unwrap!(limited.merge(&lhs));
+        assert!(limited.total_bytes() <= 6);
+    }
+
+    #[test]
+    fn merge_collision() {
+        // Identifies messages by their first byte only.
+        struct FirstByteHasher;
+
+        impl Hasher for FirstByteHasher {
+            type Digest = [u8; 1];
+
+            fn hash(data: &[u8]) -> [u8; 1] {
+                [data[0]]
+            }
+        }
+
+        let mut lhs = Gossip::<FirstByteHasher>::with_peers(20);
+        let mut rhs = Gossip::<FirstByteHasher>::with_peers(20);
+        let _ = unwrap!(lhs.new_message(b"message".to_vec()));
+        let _ = unwrap!(rhs.new_message(b"new".to_vec()));
+        let _ = unwrap!(rhs.new_message(b"more".to_vec()));
+        match lhs.merge(&rhs) {
+            Err(Error::DigestCollision) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(lhs.message_count(), 1);
+        assert_eq!(&*unwrap!(unwrap!(lhs.message(&[b'm'])).payload()), b"message");
+    }
+
+    #[test]
+    fn next_round_detailed() {
+        let mut gossip = gossip_with_network_size(21);
+        let digests: Vec<_> = (0..10)
+            .map(|index| unwrap!(gossip.new_message(vec![index])))
+            .collect();
+        let mut copy: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+
+        let push_list = gossip.next_round();
+        let detailed = copy.next_round_detailed();
+        assert_eq!(detailed.len(), push_list.len());
+        for (item, rpc) in detailed.iter().zip(push_list) {
+            assert!(digests.contains(&item.digest));
+            assert_eq!(item.state.our_counter(), Some(item.counter));
+            assert_eq!(
+                rpc,
+                GossipRpc::Push {
+                    msg: item.msg.clone(),
+                    counter: item.counter,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn pull_propagates_new_message() {
+        let pusher_id = peer_id(0);
+        let puller_id = peer_id(1);
+        let mut pusher = gossip_with_network_size(21);
+        let mut puller = gossip_with_network_size(21);
+        let digest = unwrap!(puller.new_message(b"message".to_vec()));
+
+        // The pusher has nothing to push, so sends an empty Push which acts as a fetch request.
+        let push_list = pusher.next_round();
+        assert_eq!(push_list.len(), 1);
+        let mut pull_list = vec![];
+        for push in push_list {
+            pull_list.extend(unwrap!(puller.receive(pusher_id, push)));
+        }
+        assert_eq!(pull_list.len(), 1);
+        for pull in pull_list {
+            assert!(unwrap!(pusher.receive(puller_id, pull)).is_empty());
+        }
+        assert!(pusher.has_message(&digest));
+        assert_eq!(pusher.message_phase(&digest), Some(Phase::B));
+    }
+
+    #[test]
+    fn next_round_sorted_by_counter() {
+        let mut gossip = gossip_with_network_size(21);
+        for index in 0..10 {
+            let rpc = GossipRpc::Push {
+                msg: vec![index].into(),
+                counter: if index % 2 == 0 { 1 } else { u8::max_value() },
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index as usize), rpc));
+        }
+        let mut copy: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+
+        let sorted = copy.next_round_sorted_by_counter();
+        let counters: Vec<u8> = sorted
+            .iter()
+            .map(|rpc| match *rpc {
+                GossipRpc::Push { counter, .. } => counter,
+                GossipRpc::Pull { .. } => panic!("Unexpected Pull"),
+            })
+            .collect();
+        assert_eq!(counters, vec![1, 1, 1, 1, 1, 255, 255, 255, 255, 255]);
+
+        // The same RPCs are produced, and the state is advanced identically.
+        let mut unsorted = gossip.next_round();
+        unsorted.sort_by_key(|rpc| match *rpc {
+            GossipRpc::Push { counter, .. } => counter,
+            GossipRpc::Pull { .. } => panic!("Unexpected Pull"),
+        });
+        assert_eq!(sorted, unsorted);
+        assert_eq!(format!("{:?}", copy), format!("{:?}", gossip));
+    }
+
+    #[test]
+    fn push_budget() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_max_push_per_round(Some(3));
+        let digests: Vec<_> = (0..10)
+            .map(|index| unwrap!(gossip.new_message(vec![index])))
+            .collect();
+
+        // Each message should be pushed exactly once over the first three rounds, apart from one
+        // which is held back until the fourth.
+        let mut pushed = BTreeSet::new();
+        for _ in 0..3 {
+            let push_list = gossip.next_round_detailed();
+            assert_eq!(push_list.len(), 3);
+            for item in push_list {
+                assert!(pushed.insert(item.digest));
+                assert_eq!(item.state.rounds(), Some(1));
+            }
+        }
+        let held_back = digests
+            .iter()
+            .find(|digest| !pushed.contains(*digest))
+            .cloned();
+        let held_back = unwrap!(held_back);
+        assert_eq!(gossip.messages[&held_back].state.rounds(), Some(0));
+
+        // The held-back message takes priority in the next round.
+        let push_list = gossip.next_round_detailed();
+        assert_eq!(push_list.len(), 3);
+        assert!(push_list.iter().any(|item| item.digest == held_back));
+        assert_eq!(gossip.messages[&held_back].state.rounds(), Some(1));
+    }
+
+    #[test]
+    fn last_round_skipped() {
+        let mut gossip = gossip_with_network_size(21);
+        let mut digests: Vec<_> = (0..5)
+            .map(|index| unwrap!(gossip.new_message(vec![index])))
+            .collect();
+        digests.sort();
+        let _ = gossip.next_round();
+        assert!(gossip.last_round_skipped().is_empty());
+
+        gossip.set_max_push_per_round(Some(2));
+        let pushed: Vec<_> = gossip
+            .next_round_detailed()
+            .into_iter()
+            .map(|item| item.digest)
+            .collect();
+        let skipped = gossip.last_round_skipped().to_vec();
+        assert_eq!(skipped.len(), 3);
+        let mut all: Vec<_> = pushed.iter().chain(&skipped).cloned().collect();
+        all.sort();
+        assert_eq!(all, digests);
+
+        // The skipped messages are pushed first next time.
+        let pushed: Vec<_> = gossip
+            .next_round_detailed()
+            .into_iter()
+            .map(|item| item.digest)
+            .collect();
+        assert_eq!(pushed, &skipped[..2]);
+        gossip.clear();
+        assert!(gossip.last_round_skipped().is_empty());
+    }
+
+    #[test]
+    fn current_round() {
+        let mut gossip = gossip_with_network_size(21);
+        assert_eq!(gossip.current_round(), 0);
+        let _ = gossip.next_round();
+        let _ = gossip.next_round_detailed();
+        let _ = gossip.next_round_sorted_by_counter();
+        assert_eq!(gossip.current_round(), 3);
+        let restored: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+        assert_eq!(restored.current_round(), 3);
+        gossip.clear();
+        assert_eq!(gossip.current_round(), 0);
+    }
+
+    #[test]
+    fn terminated_after_max_c_rounds() {
+        let mut gossip = gossip_with_network_size(21);
+        let rpc = GossipRpc::Push {
+            msg: b"message".to_vec().into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let digest = hash_message(b"message");
+        assert!(gossip.max_c_rounds() < gossip.max_rounds());
+        for _ in 1..gossip.max_c_rounds() {
+            let _ = gossip.next_round();
+            assert!(!gossip.is_terminated(&digest));
+        }
+        let _ = gossip.next_round();
+        assert!(gossip.is_terminated(&digest));
+    }
+
+    #[test]
+    fn refresh() {
+        let mut gossip = gossip_with_network_size(21);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        for _ in 0..gossip.max_rounds() {
+            let _ = gossip.next_round();
+        }
+        assert!(gossip.is_terminated(&digest));
+        assert!(gossip.next_round_detailed().is_empty());
+
+        assert!(gossip.refresh(&digest));
+        assert_eq!(gossip.message_phase(&digest), Some(Phase::B));
+        assert_eq!(gossip.messages[&digest].first_round, 0);
+        let push_list = gossip.next_round_detailed();
+        assert_eq!(push_list.len(), 1);
+        assert_eq!(push_list[0].digest, digest);
+        assert!(!gossip.refresh(&hash_message(b"other")));
+    }
+
+    #[test]
+    fn max_rehot() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_max_rehot(Some(2));
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        // Refreshing while still in state B doesn't count towards the limit.
+        assert!(gossip.refresh(&digest));
+        for _ in 0..2 {
+            for _ in 0..gossip.max_rounds() {
+                let _ = gossip.next_round();
+            }
+            assert!(gossip.refresh(&digest));
+            assert_eq!(gossip.message_phase(&digest), Some(Phase::B));
+        }
+        assert_eq!(gossip.messages[&digest].rehot_count, 2);
+
+        // Once the limit is reached, a message in state C is terminated rather than refreshed.
+        let rpc = GossipRpc::Push {
+            msg: b"message".to_vec().into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let _ = gossip.next_round();
+        assert_eq!(gossip.message_phase(&digest), Some(Phase::C));
+        let _ = gossip.drain_events();
+        assert!(gossip.refresh(&digest));
+        assert!(gossip.is_terminated(&digest));
+        assert_eq!(gossip.drain_events(), vec![GossipEvent::Terminated(digest)]);
+        assert!(gossip.refresh(&digest));
+        assert!(gossip.is_terminated(&digest));
+        assert!(gossip.next_round_detailed().is_empty());
+    }
+
+    #[test]
+    fn rounds_until_terminate() {
+        let mut gossip = gossip_with_network_size(21);
+        assert!(gossip.rounds_until_terminate(&hash_message(b"message")).is_none());
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        for expected in (1..gossip.max_rounds() + 1).rev() {
+            assert_eq!(gossip.rounds_until_terminate(&digest), Some(expected));
+            let _ = gossip.next_round();
+        }
+        assert!(gossip.is_terminated(&digest));
+        assert!(gossip.rounds_until_terminate(&digest).is_none());
+    }
+
+    #[test]
+    fn new_message_prehashed() {
+        let msg = b"a message streamed in several chunks".to_vec();
+        let mut hasher = MessageHasher::new();
+        for chunk in msg.chunks(5) {
+            hasher.update(chunk);
+        }
+        let digest = hasher.finish();
+        assert_eq!(digest, hash_message(&msg));
+
+        let mut gossip = gossip_with_network_size(21);
+        assert_eq!(unwrap!(gossip.new_message_prehashed(digest, msg.clone())), digest);
+        assert!(gossip.has_message_bytes(&msg));
+        assert_eq!(unwrap!(gossip.new_message(msg)), digest);
+        assert_eq!(gossip.message_count(), 1);
+        match gossip.new_message_prehashed(digest, b"other".to_vec()) {
+            Err(Error::DigestCollision) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match gossip.new_message_prehashed(digest, vec![]) {
+            Err(Error::Empty) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn message_age_rounds() {
+        let mut gossip = gossip_with_network_size(21);
+        let local = unwrap!(gossip.new_message(b"local".to_vec()));
+        assert_eq!(gossip.message_age_rounds(&local), Some(0));
+        let _ = gossip.next_round();
+        let _ = gossip.next_round();
+        let rpc = GossipRpc::Push {
+            msg: b"remote".to_vec().into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let remote = hash_message(b"remote");
+        assert_eq!(gossip.messages[&remote].first_round, 2);
+        let _ = gossip.next_round();
+        assert_eq!(gossip.message_age_rounds(&local), Some(3));
+        assert_eq!(gossip.message_age_rounds(&remote), Some(1));
+        assert_eq!(gossip.message_age_rounds(&hash_message(b"other")), None);
+    }
+
+    #[test]
+    fn prune_expired() {
+        let mut gossip = gossip_with_network_size(21);
+        let old = unwrap!(gossip.new_message(b"old".to_vec()));
+        let _ = gossip.next_round();
+        let _ = gossip.next_round();
+        let new = unwrap!(gossip.new_message(b"new".to_vec()));
+
+        // No maximum age set.
+        assert_eq!(gossip.prune_expired(100), 0);
+        assert_eq!(gossip.message_count(), 2);
+
+        gossip.set_max_age_rounds(Some(3));
+        // `old` is exactly the maximum age.
+        assert_eq!(gossip.prune_expired(3), 0);
+        // `old` is one round beyond the maximum age.
+        assert_eq!(gossip.prune_expired(4), 1);
+        assert!(!gossip.has_message(&old));
+        assert!(gossip.has_message(&new));
+        // `new` is exactly the maximum age, then one round beyond it.
+        assert_eq!(gossip.prune_expired(5), 0);
+        assert_eq!(gossip.prune_expired(6), 1);
+        assert!(gossip.is_empty());
+    }
+
+    #[test]
+    fn drain_events() {
+        let mut gossip = gossip_with_network_size(21);
+        let local = unwrap!(gossip.new_message(b"local".to_vec()));
+        let rpc = GossipRpc::Push {
+            msg: b"remote".to_vec().into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let remote = hash_message(b"remote");
+        assert_eq!(
+            gossip.drain_events(),
+            vec![GossipEvent::NewMessage(local), GossipEvent::NewMessage(remote)]
+        );
+        assert!(gossip.drain_events().is_empty());
+
+        // The counter for `remote` reaches `counter_max` on our next round.
+        for index in 1..3 {
+            let rpc = GossipRpc::Push {
+                msg: b"remote".to_vec().into(),
+                counter: 1,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index), rpc));
+        }
+        let _ = gossip.next_round();
+        assert_eq!(gossip.drain_events(), vec![GossipEvent::EnteredC(remote)]);
+
+        let mut terminated = vec![];
+        for _ in 0..gossip.max_rounds() {
+            let _ = gossip.next_round();
+            terminated.extend(gossip.drain_events());
+        }
+        assert_eq!(
+            terminated,
+            vec![GossipEvent::Terminated(remote), GossipEvent::Terminated(local)]
+        );
+    }
+
+    #[test]
+    fn events_are_bounded() {
+        let mut gossip = gossip_with_network_size(21);
+        for index in 0..(MAX_EVENTS + 1) {
+            let _ = unwrap!(gossip.new_message(format!("{}", index).into_bytes()));
+        }
+        let events = gossip.drain_events();
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0], GossipEvent::NewMessage(hash_message(b"1")));
+    }
+
+    #[test]
+    fn stats() {
+        let mut gossip = gossip_with_network_size(21);
+        assert_eq!(gossip.stats(), GossipStats::default());
+        let _ = unwrap!(gossip.new_message(b"local".to_vec()));
+        let rpc = GossipRpc::Push {
+            msg: b"remote".to_vec().into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let expected = GossipStats {
+            in_state_b: 1,
+            in_state_c: 1,
+            in_state_d: 0,
+            rounds: 0,
+            total_bytes: 11,
+        };
+        assert_eq!(gossip.stats(), expected);
+
+        for _ in 0..gossip.max_c_rounds() {
+            let _ = gossip.next_round();
+        }
+        let stats = gossip.stats();
+        assert_eq!(stats.in_state_d, 1);
+        assert_eq!(stats.in_state_b + stats.in_state_c, 1);
+        assert_eq!(stats.rounds, u64::from(gossip.max_c_rounds()));
+        assert_eq!(stats.total_bytes, 11);
+    }
+
+    #[test]
+    fn rpcs_share_payloads() {
+        let mut gossip = gossip_with_network_size(21);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        let stored = gossip.messages[&digest].msg.clone();
+        match &gossip.next_round()[..] {
+            [GossipRpc::Push { ref msg, .. }] => assert!(Arc::ptr_eq(msg, &stored)),
+            rpcs => panic!("Unexpected push list: {:?}", rpcs),
+        }
+        let rpc = GossipRpc::Push {
+            msg: b"remote".to_vec().into(),
+            counter: 1,
+        };
+        match &unwrap!(gossip.receive(peer_id(0), rpc))[..] {
+            [GossipRpc::Pull { ref msg, .. }] => assert!(Arc::ptr_eq(msg, &stored)),
+            rpcs => panic!("Unexpected pull list: {:?}", rpcs),
+        }
+    }
+
+    #[test]
+    fn small_swarm_terminates() {
+        let node_count = 21;
+        let mut nodes: Vec<Gossip> = (0..node_count)
+            .map(|_| Gossip::with_peers(node_count as u64 - 1))
+            .collect();
+        let digest = unwrap!(nodes[0].new_message(b"message".to_vec()));
+
+        let mut round = 0;
+        while !nodes.iter().all(|node| node.has_message(&digest) && node.all_terminated()) {
+            assert!(round < 3 * usize::from(nodes[0].max_rounds()), "Failed to terminate.");
+            // Each node pushes to a different peer every round, and the Pulls are passed back.
+            for src in 0..node_count {
+                let dst = (src + 1 + round % (node_count - 1)) % node_count;
+                for push in nodes[src].next_round() {
+                    let pulls = unwrap!(nodes[dst].receive(peer_id(src), push));
+                    for pull in pulls {
+                        assert!(unwrap!(nodes[src].receive(peer_id(dst), pull)).is_empty());
+                    }
+                }
+            }
+            round += 1;
+        }
+        // Some nodes will have moved to state C on the evidence of their peers' counters.
+        assert!(nodes.iter_mut().any(|node| {
+            node.drain_events().contains(&GossipEvent::EnteredC(digest))
+        }));
+    }
+
+    #[test]
+    fn serving_pulls_counts_as_evidence() {
+        let mut supported = gossip_with_network_size(21);
+        let mut contested = gossip_with_network_size(21);
+        for gossip in &mut [&mut supported, &mut contested] {
+            let _ = unwrap!(gossip.new_message(b"message".to_vec()));
+            for index in 0..2 {
+                let rpc = GossipRpc::Push {
+                    msg: b"message".to_vec().into(),
+                    counter: 1,
+                };
+                let _ = unwrap!(gossip.receive(peer_id(index), rpc));
+            }
+        }
+        // Peers which are only served Pulls count as not having the message.
+        for index in 2..5 {
+            let rpc = GossipRpc::Push {
+                msg: b"other".to_vec().into(),
+                counter: 1,
+            };
+            assert!(!unwrap!(contested.receive(peer_id(index), rpc)).is_empty());
+        }
+        let digest = hash_message(b"message");
+        let _ = supported.next_round();
+        let _ = contested.next_round();
+        assert_eq!(supported.message_phase(&digest), Some(Phase::C));
+        assert_eq!(contested.messages[&digest].state.our_counter(), Some(1));
+    }
+
+    #[test]
+    fn peek_next_round() {
+        let mut gossip = gossip_with_network_size(21);
+        let empty_push = vec![
+            GossipRpc::Push {
+                msg: Vec::new().into(),
+                counter: 0,
+            },
+        ];
+        assert_eq!(gossip.peek_next_round(), empty_push);
+        for index in 0..3 {
+            let _ = unwrap!(gossip.new_message(vec![index]));
+        }
+        let rpc = GossipRpc::Push {
+            msg: vec![0].into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        gossip.set_max_push_per_round(Some(2));
+
+        for _ in 0..gossip.max_rounds() + 1 {
+            let round = gossip.current_round();
+            let peeked = gossip.peek_next_round();
+            assert_eq!(peeked, gossip.peek_next_round());
+            assert_eq!(round, gossip.current_round());
+            assert_eq!(peeked, gossip.next_round());
+        }
+        assert_eq!(gossip.peek_next_round(), empty_push);
+    }
+
+    #[test]
+    fn pending_push_count() {
+        let mut gossip = gossip_with_network_size(21);
+        assert_eq!(gossip.pending_push_count(), 0);
+        for index in 0..3 {
+            let _ = unwrap!(gossip.new_message(vec![index]));
+        }
+        gossip.set_max_push_per_round(Some(2));
+        for _ in 0..gossip.max_rounds() + 1 {
+            let count = gossip.pending_push_count();
+            let peeked = gossip.peek_next_round();
+            if count == 0 {
+                let empty_push = GossipRpc::Push {
+                    msg: Vec::new().into(),
+                    counter: 0,
+                };
+                assert_eq!(peeked, vec![empty_push]);
+            } else {
+                assert_eq!(peeked.len(), count);
+            }
+            assert_eq!(gossip.next_round_detailed().len(), count);
+        }
+        assert_eq!(gossip.pending_push_count(), 0);
+    }
+
+    #[test]
+    fn receive_batch() {
+        let rpcs = || {
+            (0..3)
+                .map(|index| {
+                    GossipRpc::Push {
+                        msg: vec![index].into(),
+                        counter: 1,
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut batched = gossip_with_network_size(21);
+        let mut individual = gossip_with_network_size(21);
+        for gossip in &mut [&mut batched, &mut individual] {
+            let _ = unwrap!(gossip.new_message(vec![0]));
+            let _ = unwrap!(gossip.new_message(vec![9]));
+        }
+        let responses = unwrap!(batched.receive_batch(peer_id(0), rpcs()));
+        let mut expected = vec![];
+        for rpc in rpcs() {
+            expected.extend(unwrap!(individual.receive(peer_id(0), rpc)));
+        }
+        assert_eq!(responses, expected);
+        assert_eq!(batched.message_count(), individual.message_count());
+        assert_eq!(batched.next_round(), individual.next_round());
+
+        // An invalid RPC rejects the whole batch.
+        let mut invalid = rpcs();
+        invalid.push(GossipRpc::Pull {
+            msg: Vec::new().into(),
+            counter: 1,
+        });
+        let mut gossip = gossip_with_network_size(21);
+        match gossip.receive_batch(peer_id(0), invalid) {
+            Err(Error::Empty) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(gossip.is_empty());
+        assert!(gossip.peers_in_this_round.is_empty());
+    }
+
+    #[test]
+    fn short_digests() {
+        struct TruncatedHasher;
+
+        impl Hasher for TruncatedHasher {
+            type Digest = [u8; 16];
+
+            fn hash(data: &[u8]) -> [u8; 16] {
+                let mut digest = [0; 16];
+                digest.copy_from_slice(&hash_message(data)[..16]);
+                digest
+            }
+        }
+
+        let mut gossip: Gossip<TruncatedHasher> = Gossip::with_peers(20);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        assert_eq!(digest[..], hash_message(b"message")[..16]);
+        assert!(gossip.has_message_bytes(b"message"));
+        assert!(round_trip(&gossip).has_message(&digest));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_payloads() {
+        let msg = b"message message message message message".to_vec();
+        let mut plain = gossip_with_network_size(21);
+        let mut compressed = gossip_with_network_size(21);
+        compressed.set_compress_payloads(true);
+        let digest = unwrap!(plain.new_message(msg.clone()));
+        assert_eq!(unwrap!(compressed.new_message(msg.clone())), digest);
+        let stored = unwrap!(compressed.message(&digest));
+        assert!(stored.compressed);
+        assert_ne!(&stored.msg[..], &msg[..]);
+        assert_eq!(&unwrap!(stored.payload())[..], &msg[..]);
+        assert_eq!(compressed.messages(), vec![msg.clone()]);
+
+        // Messages received from peers are stored compressed too, but still sent uncompressed.
+        let rpc = GossipRpc::Push {
+            msg: b"remote".to_vec().into(),
+            counter: 1,
+        };
+        let pulls = unwrap!(compressed.receive(peer_id(0), rpc));
+        assert_eq!(
+            pulls,
+            vec![
+                GossipRpc::Pull {
+                    msg: msg.clone().into(),
+                    counter: 1,
+                },
+            ]
+        );
+        assert!(unwrap!(compressed.message(&hash_message(b"remote"))).compressed);
+        let mut restored = round_trip(&compressed);
+        let mut pushed: Vec<Vec<u8>> = restored
+            .next_round()
+            .into_iter()
+            .map(|rpc| match rpc {
+                GossipRpc::Push { msg, .. } => msg.to_vec(),
+                GossipRpc::Pull { .. } => panic!("Unexpected Pull"),
+            })
+            .collect();
+        pushed.sort();
+        assert_eq!(pushed, vec![msg.clone(), b"remote".to_vec()]);
+
+        // A copy seen again is checked against the cached hash rather than by decompressing the
+        // held one.
+        unwrap!(restored.messages.get_mut(&digest)).msg = b"corrupted".to_vec().into();
+        let _ = unwrap!(restored.new_message(msg.clone()));
+        unwrap!(restored.messages.get_mut(&digest)).uncompressed_hash = Some([0; 32]);
+        match restored.new_message(msg) {
+            Err(Error::DigestCollision) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compressed_snapshot_unsupported() {
+        let gossip = gossip_with_network_size(21);
+        let mut snapshot = gossip.snapshot();
+        snapshot.config.compress_payloads = true;
+        match Gossip::<Sha3Hasher>::from_snapshot(snapshot) {
+            Err(Error::CompressionUnsupported) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn max_total_bytes() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_max_total_bytes(Some(12));
+        let oldest = unwrap!(gossip.new_message(vec![0; 4]));
+        let _ = gossip.next_round();
+        let older = unwrap!(gossip.new_message(vec![1; 4]));
+        let _ = gossip.next_round();
+        // Moves straight to state C.
+        let rpc = GossipRpc::Push {
+            msg: vec![2; 4].into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let cold = hash_message(&[2; 4]);
+        assert_eq!(gossip.total_bytes(), 12);
+
+        // The message in state C goes first, then the one in state B with the most rounds.
+        let newest = unwrap!(gossip.new_message(vec![3; 4]));
+        assert!(!gossip.has_message(&cold));
+        assert_eq!(gossip.total_bytes(), 12);
+        let _ = unwrap!(gossip.new_message(vec![4; 8]));
+        assert!(!gossip.has_message(&oldest));
+        assert!(!gossip.has_message(&older));
+        assert!(gossip.has_message(&newest));
+        assert_eq!(gossip.total_bytes(), 12);
+
+        // A new message is kept even if it alone exceeds the limit.
+        let huge = unwrap!(gossip.new_message(vec![5; 20]));
+        assert_eq!(gossip.message_count(), 1);
+        assert!(gossip.has_message(&huge));
+    }
+
+    #[test]
+    fn clone() {
+        let mut gossip = gossip_with_network_size(21);
+        for index in 0..3 {
+            let _ = unwrap!(gossip.new_message(vec![index]));
+        }
+        let rpc = GossipRpc::Push {
+            msg: vec![0].into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let mut clone = gossip.clone();
+        assert_eq!(clone.drain_events(), gossip.drain_events());
+        for index in 0..gossip.max_rounds() {
+            let rpc = GossipRpc::Pull {
+                msg: vec![index % 3].into(),
+                counter: 1,
+            };
+            for gossip in &mut [&mut gossip, &mut clone] {
+                let _ = unwrap!(gossip.receive(peer_id(usize::from(index)), rpc.clone()));
+            }
+            assert_eq!(clone.next_round(), gossip.next_round());
+            assert_eq!(clone.drain_events(), gossip.drain_events());
+        }
+    }
+
+    #[test]
+    fn debug() {
+        let mut gossip = gossip_with_network_size(21);
+        let digest = unwrap!(gossip.new_message(vec![0xab; 1000]));
+        let output = format!("{:?}", gossip);
+        let expected = format!(
+            "Gossip {{ messages: {{ {:02x}{:02x}{:02x}{:02x}..: B {{ counter: Some(1), rounds: \
+             Some(0), msg: abababab.. (1000 bytes) }}, }}, network_size: 21, counter_max: 2, \
+             max_c_rounds: 2, max_rounds: 4, peers_in_this_round: {{}} }}",
+            digest[0],
+            digest[1],
+            digest[2],
+            digest[3]
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn receive_filtered() {
+        let mut gossip = gossip_with_network_size(21);
+        let _ = unwrap!(gossip.new_message(vec![0; 10]));
+        let _ = unwrap!(gossip.new_message(vec![1; 2]));
+        let rpc = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let pulls = unwrap!(gossip.receive_filtered(peer_id(0), rpc.clone(), |digest, msg| {
+            assert_eq!(*digest, hash_message(msg));
+            msg.len() < 5
+        }));
+        let expected = vec![
+            GossipRpc::Pull {
+                msg: vec![1; 2].into(),
+                counter: 1,
+            },
+        ];
+        assert_eq!(pulls, expected);
+
+        // If everything is filtered out, an empty Pull is sent.
+        let pulls = unwrap!(gossip.receive_filtered(peer_id(1), rpc, |_, _| false));
+        let expected = vec![
+            GossipRpc::Pull {
+                msg: Vec::new().into(),
+                counter: 0,
+            },
+        ];
+        assert_eq!(pulls, expected);
+    }
+
+    #[test]
+    fn last_round_median_bumps() {
+        let mut gossip = gossip_with_network_size(21);
+        let digests: Vec<_> = (0..3)
+            .map(|index| unwrap!(gossip.new_message(vec![index])))
+            .collect();
+        // Message 0: two peers with counter 1 against one without the message, so bumped.
+        // Message 1: one peer with counter 1 against two without the message, so not bumped.
+        // Message 2: one peer in state C, so moves to state C without a bump.
+        for index in 0..2 {
+            let rpc = GossipRpc::Push {
+                msg: vec![0].into(),
+                counter: 1,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index), rpc));
+        }
+        let rpc = GossipRpc::Push {
+            msg: vec![1].into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let rpc = GossipRpc::Push {
+            msg: vec![2].into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        let rpc = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(2), rpc));
+
+        assert!(gossip.last_round_median_bumps().is_empty());
+        let _ = gossip.next_round();
+        assert_eq!(gossip.last_round_median_bumps(), &digests[..1]);
+        assert_eq!(gossip.message_phase(&digests[1]), Some(Phase::B));
+        assert_eq!(gossip.message_phase(&digests[2]), Some(Phase::C));
+        let _ = gossip.next_round();
+        assert!(gossip.last_round_median_bumps().is_empty());
+    }
+
+    #[test]
+    fn receive_ref() {
+        let mut by_ref = gossip_with_network_size(21);
+        let mut by_value = gossip_with_network_size(21);
+        for gossip in &mut [&mut by_ref, &mut by_value] {
+            let _ = unwrap!(gossip.new_message(b"local".to_vec()));
+        }
+        let digest = hash_message(b"remote");
+        for index in 0..3 {
+            let responses =
+                unwrap!(by_ref.receive_ref(peer_id(index), RpcKind::Push, 1, b"remote"));
+            let rpc = GossipRpc::Push {
+                msg: b"remote".to_vec().into(),
+                counter: 1,
+            };
+            assert_eq!(responses, unwrap!(by_value.receive(peer_id(index), rpc)));
+        }
+        // The copy stored when the message was first received is kept.
+        let stored = by_ref.messages[&digest].msg.clone();
+        let _ = unwrap!(by_ref.receive_ref(peer_id(3), RpcKind::Pull, 1, b"remote"));
+        assert!(Arc::ptr_eq(&stored, &by_ref.messages[&digest].msg));
+        assert_eq!(peer_counter_count(&by_ref, &digest), 3);
+
+        match by_ref.receive_ref(peer_id(4), RpcKind::Pull, 1, b"") {
+            Err(Error::Empty) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn receive_weighted() {
+        let mut weighted = gossip_with_network_size(21);
+        let digest = unwrap!(weighted.new_message(b"message".to_vec()));
+        let mut unweighted: Gossip = unwrap!(Gossip::from_snapshot(weighted.snapshot()));
+        let empty_push = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let push = GossipRpc::Push {
+            msg: b"message".to_vec().into(),
+            counter: 1,
+        };
+        for gossip in &mut [&mut weighted, &mut unweighted] {
+            // Two peers in state A.
+            let _ = unwrap!(gossip.receive_weighted(peer_id(0), empty_push.clone(), 1));
+            let _ = unwrap!(gossip.receive(peer_id(1), empty_push.clone()));
+        }
+        let _ = unwrap!(weighted.receive_weighted(peer_id(2), push.clone(), 3));
+        let _ = unwrap!(unweighted.receive_weighted(peer_id(2), push, 1));
+        assert!(unweighted.peer_weights.is_empty());
+
+        weighted.verify_invariants();
+        let _ = weighted.next_round();
+        let _ = unweighted.next_round();
+        assert_eq!(weighted.message_phase(&digest), Some(Phase::C));
+        assert_eq!(weighted.last_round_median_bumps(), &[digest]);
+        assert!(weighted.peer_weights.is_empty());
+        assert_eq!(unweighted.message_phase(&digest), Some(Phase::B));
+        assert!(unweighted.last_round_median_bumps().is_empty());
+    }
+
+    #[test]
+    fn pull_list_for() {
+        let mut gossip = gossip_with_network_size(21);
+        let terminated = unwrap!(gossip.new_message(b"terminated".to_vec()));
+        for _ in 0..gossip.max_rounds() {
+            let _ = gossip.next_round();
+        }
+        let held = unwrap!(gossip.new_message(b"held".to_vec()));
+        let absent = hash_message(b"absent");
+        let sent = gossip.statistics().full_message_sent;
+
+        let pulls = gossip.pull_list_for(&[absent, held, terminated]);
+        assert_eq!(pulls.len(), 1);
+        match pulls[0] {
+            GossipRpc::Pull { ref msg, counter } => {
+                assert_eq!(&**msg, b"held");
+                assert_eq!(counter, 1);
+            }
+            ref rpc => panic!("Unexpected RPC: {:?}", rpc),
+        }
+        assert!(gossip.pull_list_for(&[]).is_empty());
+        assert_eq!(gossip.statistics().full_message_sent, sent);
+    }
+
+    #[test]
+    fn advance_round() {
+        let mut gossip = gossip_with_network_size(21);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        for _ in 1..gossip.max_rounds() {
+            gossip.advance_round();
+            assert!(!gossip.is_terminated(&digest));
+        }
+        gossip.advance_round();
+        assert!(gossip.is_terminated(&digest));
+        assert_eq!(gossip.current_round(), u64::from(gossip.max_rounds()));
+        assert_eq!(gossip.statistics().full_message_sent, 0);
+        assert_eq!(gossip.statistics().empty_push_sent, 0);
+        assert_eq!(gossip.statistics().bytes_pushed, 0);
+    }
+
+    #[test]
+    fn advance_round_ignores_push_budget() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_max_push_per_round(Some(1));
+        let digests: Vec<_> = (0..5)
+            .map(|index| unwrap!(gossip.new_message(vec![index])))
+            .collect();
+        for _ in 0..gossip.max_rounds() {
+            gossip.advance_round();
+            assert!(gossip.last_round_skipped().is_empty());
+        }
+        for digest in &digests {
+            assert_eq!(gossip.message_phase(digest), Some(Phase::D));
+        }
+    }
+
+    #[test]
+    fn new_message_from_peer_counters() {
+        for &(counter, phase) in &[(0, Phase::B), (2, Phase::C), (3, Phase::C), (255, Phase::C)] {
+            let mut gossip = gossip_with_network_size(21);
+            gossip.set_rounds(2, 3, 6);
+            let rpc = GossipRpc::Push {
+                msg: b"message".to_vec().into(),
+                counter,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+            let digest = hash_message(b"message");
+            assert_eq!(gossip.message_phase(&digest), Some(phase));
+            assert_eq!(unwrap!(gossip.message(&digest)).state.rounds(), Some(0));
+
+            // However large the peer's counter, the message is pushed rather than born terminated.
+            let push_list = gossip.next_round_detailed();
+            assert_eq!(push_list.len(), 1);
+            assert_eq!(push_list[0].digest, digest);
+        }
+    }
+
+    #[test]
+    fn pull_list_limited() {
+        let mut gossip = gossip_with_network_size(21);
+        gossip.set_rounds(3, 3, 6);
+        let first = unwrap!(gossip.new_message(b"first".to_vec()));
+        let _ = gossip.next_round();
+        let second = unwrap!(gossip.new_message(b"second".to_vec()));
+        let rpc = GossipRpc::Push {
+            msg: b"cold".to_vec().into(),
+            counter: gossip.counter_max(),
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        // Advance `first` to a counter of 2, leaving `second` at 1.
+        for index in 1..3 {
+            let rpc = GossipRpc::Push {
+                msg: b"first".to_vec().into(),
+                counter: 1,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(index), rpc));
+        }
+
+        let counters = |pulls: Vec<GossipRpc>| -> Vec<(Vec<u8>, u8)> {
+            pulls
+                .into_iter()
+                .map(|rpc| match rpc {
+                    GossipRpc::Pull { msg, counter } => (msg.to_vec(), counter),
+                    rpc => panic!("Unexpected RPC: {:?}", rpc),
+                })
+                .collect()
+        };
+        let mut ascending = vec![(b"first".to_vec(), 1), (b"second".to_vec(), 1)];
+        if second < first {
+            ascending.reverse();
+        }
+        ascending.push((b"cold".to_vec(), u8::MAX));
+        assert_eq!(counters(gossip.pull_list_limited(10)), ascending);
+        assert_eq!(counters(gossip.pull_list_limited(2)), &ascending[..2]);
+        assert!(gossip.pull_list_limited(0).is_empty());
+
+        let _ = gossip.next_round();
+        assert_eq!(unwrap!(gossip.message_ref(&first)).counter(), Some(2));
+        let limited = counters(gossip.pull_list_limited(1));
+        assert_eq!(limited, vec![(b"second".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn pull_list() {
+        let mut gossip = gossip_with_network_size(21);
+        assert_eq!(gossip.pull_list_len(), 0);
+        assert!(gossip.pull_list_digests().is_empty());
+
+        let mut digests: Vec<_> = (0..3)
+            .map(|index| unwrap!(gossip.new_message(vec![index])))
+            .collect();
+        digests.sort();
+        assert_eq!(gossip.pull_list_len(), 3);
+        assert_eq!(gossip.pull_list_digests(), digests);
+        // Querying the pull list doesn't affect the state.
+        assert_eq!(gossip.pull_list_len(), 3);
+        assert_eq!(gossip.statistics().full_message_sent, 0);
+        assert!(gossip.peers_in_this_round.is_empty());
+
+        let push = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let responses = unwrap!(gossip.receive(peer_id(0), push));
+        assert_eq!(responses.len(), gossip.pull_list_len());
+
+        // Messages in state D are no longer pulled.
+        for _ in 0..gossip.max_rounds() {
+            let _ = gossip.next_round();
+        }
+        assert_eq!(gossip.message_count(), 3);
+        assert_eq!(gossip.pull_list_len(), 0);
+    }
+
+    #[test]
+    fn trusted_counter_cap() {
+        let mut gossip = gossip_with_network_size(21);
+        let local = unwrap!(gossip.new_message(b"local".to_vec()));
+        gossip.set_trusted_counter_cap(Some(1));
+        let push = |msg: &[u8]| {
+            GossipRpc::Push {
+                msg: msg.to_vec().into(),
+                counter: u8::MAX,
+            }
+        };
+
+        // Inflated counters can neither start a new message in state C nor move one there.
+        let remote = hash_message(b"remote");
+        let _ = unwrap!(gossip.receive(peer_id(0), push(b"remote")));
+        let _ = unwrap!(gossip.receive(peer_id(0), push(b"local")));
+        assert_eq!(gossip.message_phase(&remote), Some(Phase::B));
+        let counter = gossip
+            .peer_counters(&local)
+            .and_then(|counters| counters.get(&peer_id(0)).cloned());
+        assert_eq!(counter, Some(1));
+        // Two peers in state A outvote the one claiming state C.
+        let empty_push = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        for index in 1..3 {
+            let _ = unwrap!(gossip.receive(peer_id(index), empty_push.clone()));
+        }
+        let _ = gossip.next_round();
+        assert_eq!(gossip.message_phase(&local), Some(Phase::B));
+
+        // Without the cap, the one peer claiming state C moves the message there regardless.
+        gossip.set_trusted_counter_cap(None);
+        let _ = unwrap!(gossip.receive(peer_id(0), push(b"local")));
+        for index in 1..3 {
+            let _ = unwrap!(gossip.receive(peer_id(index), empty_push.clone()));
+        }
+        let _ = gossip.next_round();
+        assert_eq!(gossip.message_phase(&local), Some(Phase::C));
+    }
+
+    #[test]
+    fn drain_messages() {
+        let mut gossip = gossip_with_network_size(21);
+        let mut expected: Vec<_> = (0..5)
+            .map(|index| {
+                let msg = vec![index; 10];
+                (unwrap!(gossip.new_message(msg.clone())), msg)
+            })
+            .collect();
+        let rpc = GossipRpc::Push {
+            msg: vec![0; 10].into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        expected.sort();
+
+        gossip.verify_invariants();
+        assert_eq!(unwrap!(gossip.drain_messages()), expected);
+        assert!(gossip.is_empty());
+        assert_eq!(gossip.total_bytes(), 0);
+        assert!(gossip.peer_counters(&expected[0].0).is_none());
+        assert_eq!(gossip.statistics().full_message_received, 1);
+        assert!(unwrap!(gossip.drain_messages()).is_empty());
+
+        // A message which can't be decompressed fails the drain, leaving everything held.
+        let good = unwrap!(gossip.new_message(b"good".to_vec()));
+        let bad = unwrap!(gossip.new_message(b"bad".to_vec()));
+        unwrap!(gossip.messages.get_mut(&bad)).compressed = true;
+        assert!(gossip.drain_messages().is_err());
+        assert!(gossip.has_message(&good));
+        assert!(gossip.has_message(&bad));
+    }
+
+    #[test]
+    fn round_hit_histogram() {
+        let mut gossip = gossip_with_network_size(21);
+        let mut copy: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+        gossip.set_hit_histogram_enabled(true);
+        for gossip in &mut [&mut gossip, &mut copy] {
+            for index in 0..3 {
+                let _ = unwrap!(gossip.new_message(vec![index]));
+            }
+            // Message 0 is received from two peers and message 1 from one.
+            for index in 0..3 {
+                let rpc = GossipRpc::Push {
+                    msg: vec![index / 2].into(),
+                    counter: 1,
+                };
+                let _ = unwrap!(gossip.receive(peer_id(index as usize), rpc));
+            }
+        }
+        assert!(gossip.round_hit_histogram().is_empty());
+
+        // Recording the histogram doesn't affect propagation.
+        assert_eq!(gossip.next_round(), copy.next_round());
+        assert_eq!(gossip.round_hit_histogram(), &[1, 1, 1]);
+        // Only messages 1 and 2 remain in state B, and neither is received again.
+        assert_eq!(gossip.next_round(), copy.next_round());
+        assert_eq!(gossip.round_hit_histogram(), &[3, 1, 1]);
+        assert!(copy.round_hit_histogram().is_empty());
+
+        gossip.set_hit_histogram_enabled(true);
+        assert_eq!(gossip.round_hit_histogram(), &[3, 1, 1]);
+        gossip.set_hit_histogram_enabled(false);
+        assert!(gossip.round_hit_histogram().is_empty());
+    }
+
+    #[test]
+    fn new_messages() {
+        let mut gossip = gossip_with_network_size(21);
+        let existing = unwrap!(gossip.new_message(b"existing".to_vec()));
+        let msgs = vec![
+            b"first".to_vec(),
+            b"existing".to_vec(),
+            b"second".to_vec(),
+            b"first".to_vec(),
+        ];
+        let digests = unwrap!(gossip.new_messages(msgs.clone()));
+        let expected: Vec<_> = msgs.iter().map(|msg| hash_message(msg)).collect();
+        assert_eq!(digests, expected);
+        assert_eq!(digests[1], existing);
+        assert_eq!(gossip.message_count(), 3);
+
+        // An invalid message prevents any from being added.
+        match gossip.new_messages(vec![b"third".to_vec(), vec![]]) {
+            Err(Error::Empty) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(!gossip.has_message_bytes(b"third"));
+        assert!(unwrap!(gossip.new_messages(vec![])).is_empty());
+    }
+
+    #[test]
+    fn min_rounds() {
+        let mut small = gossip_with_network_size(2);
+        assert_eq!(
+            rounds(&small),
+            (
+                Gossip::<Sha3Hasher>::MIN_HOT_ROUNDS,
+                Gossip::<Sha3Hasher>::MIN_COLD_ROUNDS,
+                Gossip::<Sha3Hasher>::MIN_TOTAL_ROUNDS,
+            )
+        );
+        small.set_min_rounds(2, 0, 4);
+        assert_eq!(rounds(&small), (2, 1, 4));
+        // The minimums persist as the network size changes.
+        small.add_peer();
+        assert_eq!(rounds(&small), (2, 1, 4));
+
+        let mut large = gossip_with_network_size(1_000);
+        let expected = rounds(&large);
+        large.set_min_rounds(2, 0, 4);
+        assert_eq!(rounds(&large), expected);
+
+        let built: Gossip = GossipBuilder::new().total_peers(1).min_rounds(2, 0, 4).build();
+        assert_eq!(rounds(&built), (2, 1, 4));
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't match its hash")]
+    fn verify_invariants() {
+        let mut gossip = gossip_with_network_size(21);
+        let digest = unwrap!(gossip.new_message(b"message".to_vec()));
+        gossip.verify_invariants();
+        unwrap!(gossip.messages.get_mut(&digest)).msg = b"corrupted".to_vec().into();
+        gossip.verify_invariants();
+    }
+
+    #[test]
+    fn next_round_since() {
+        let mut gossip = gossip_with_network_size(1_000);
+        let old = unwrap!(gossip.new_message(b"old".to_vec()));
+        let bumped = unwrap!(gossip.new_message(b"bumped".to_vec()));
+        let _ = gossip.next_round();
+        let mut copy: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+
+        // During round 1, learn a new message and have a peer bump the counter of another.
+        let new = unwrap!(gossip.new_message(b"new".to_vec()));
+        let _ = unwrap!(copy.new_message(b"new".to_vec()));
+        for gossip in &mut [&mut gossip, &mut copy] {
+            let rpc = GossipRpc::Push {
+                msg: b"bumped".to_vec().into(),
+                counter: 1,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        }
+        let push_list = gossip.next_round_since(0);
+        assert_eq!(gossip.messages[&old].last_changed_round, 0);
+        assert_eq!(gossip.messages[&bumped].last_changed_round, 1);
+        assert_eq!(gossip.messages[&new].last_changed_round, 1);
+        let mut expected: Vec<_> = vec![bumped, new];
+        expected.sort();
+        let pushed: Vec<_> = push_list
+            .iter()
+            .map(|rpc| match *rpc {
+                GossipRpc::Push { ref msg, .. } => hash_message(msg),
+                GossipRpc::Pull { .. } => panic!("Unexpected Pull"),
+            })
+            .collect();
+        assert_eq!(pushed, expected);
+        // Two pushed in each round, plus the three pulled by the peer.
+        assert_eq!(gossip.statistics().full_message_sent, 7);
+
+        // All messages advance exactly as via `next_round()`.
+        let _ = copy.next_round();
+        assert_eq!(format!("{:?}", gossip), format!("{:?}", copy));
+
+        assert_eq!(
+            gossip.next_round_since(1),
+            vec![
+                GossipRpc::Push {
+                    msg: Vec::new().into(),
+                    counter: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pressure() {
+        let mut gossip = gossip_with_network_size(21);
+        let _ = unwrap!(gossip.new_message(vec![0; 10]));
+        assert_eq!(gossip.pressure(), 0.0);
+
+        gossip.set_max_total_bytes(Some(100));
+        let mut last = gossip.pressure();
+        assert_eq!(last, 0.1);
+        for index in 1..10 {
+            let _ = unwrap!(gossip.new_message(vec![index; 10]));
+            assert!(gossip.pressure() > last);
+            last = gossip.pressure();
+        }
+        assert_eq!(last, 1.0);
+        // Eviction keeps the store from overflowing.
+        let _ = unwrap!(gossip.new_message(vec![10; 10]));
+        assert_eq!(gossip.pressure(), 1.0);
+        let _ = unwrap!(gossip.new_message(vec![11; 200]));
+        assert_eq!(gossip.pressure(), 1.0);
+
+        gossip.set_max_total_bytes(Some(0));
+        assert_eq!(gossip.pressure(), 1.0);
+        gossip.clear();
+        assert_eq!(gossip.pressure(), 0.0);
+    }
+
+    #[test]
+    fn origin() {
+        let mut gossip = gossip_with_network_size(21);
+        let local = unwrap!(gossip.new_message(b"local".to_vec()));
+        let remote = hash_message(b"remote");
+        assert_eq!(gossip.origin(&remote), None);
+        for msg in &[&b"remote"[..], &b"local"[..]] {
+            let rpc = GossipRpc::Push {
+                msg: msg.to_vec().into(),
+                counter: 1,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        }
+        // A message later started locally as well is still remote.
+        let _ = unwrap!(gossip.new_message(b"remote".to_vec()));
+        assert_eq!(gossip.origin(&local), Some(Origin::Local));
+        assert_eq!(gossip.origin(&remote), Some(Origin::Remote));
+
+        assert_eq!(round_trip(&gossip).origin(&local), Some(Origin::Local));
+    }
+
+    #[test]
+    fn retain() {
+        let mut gossip = gossip_with_network_size(1_000);
+        let digests: Vec<_> = (0..4)
+            .map(|index| unwrap!(gossip.new_message(vec![index])))
+            .collect();
+        // Peers' counters bump ours for messages 0 and 1 at the end of the round.
+        for index in 0..2 {
+            let rpc = GossipRpc::Push {
+                msg: vec![index].into(),
+                counter: 1,
+            };
+            let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        }
+        let _ = gossip.next_round();
+
+        let mut seen = vec![];
+        let removed = gossip.retain(|digest, msg, state| {
+            seen.push((*digest, msg.to_vec()));
+            state.our_counter().map_or(false, |counter| counter < 2)
+        });
+        assert_eq!(removed, 2);
+        assert_eq!(seen.len(), 4);
+        assert!(seen.iter().all(|&(ref digest, ref msg)| *digest == hash_message(msg)));
+        assert!(!gossip.has_message(&digests[0]));
+        assert!(!gossip.has_message(&digests[1]));
+        assert!(gossip.has_message(&digests[2]));
+        assert!(gossip.has_message(&digests[3]));
+        assert_eq!(gossip.retain(|_, _, _| true), 0);
+    }
+
+    #[test]
+    fn bytes_pushed_and_pulled() {
+        let mut gossip = gossip_with_network_size(21);
+        let _ = unwrap!(gossip.new_message(vec![0; 10]));
+        let _ = unwrap!(gossip.new_message(vec![1; 20]));
+        let _ = gossip.next_round();
+        assert_eq!(gossip.bytes_pushed(), 30);
+        assert_eq!(gossip.bytes_pulled(), 0);
+
+        let rpc = GossipRpc::Push {
+            msg: vec![2; 5].into(),
+            counter: 1,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        assert_eq!(gossip.bytes_pulled(), 30);
+        // Empty RPCs carry no bytes.
+        let rpc = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let _ = unwrap!(gossip.receive(peer_id(0), rpc));
+        assert_eq!(gossip.bytes_pulled(), 30);
+
+        let restored: Gossip = unwrap!(Gossip::from_snapshot(gossip.snapshot()));
+        assert_eq!(restored.bytes_pushed(), 30);
+        assert_eq!(restored.bytes_pulled(), 30);
+
+        gossip.statistics.bytes_pushed = u64::MAX - 1;
+        let _ = gossip.next_round();
+        assert_eq!(gossip.bytes_pushed(), u64::MAX);
+        let mut statistics = gossip.statistics();
+        statistics.add(&gossip.statistics());
+        assert_eq!(statistics.bytes_pushed, u64::MAX);
+    }
+
+    #[test]
+    fn rounds_after_removing_peers() {
+        let mut gossip = gossip_with_network_size(10);
+        for _ in 0..20 {
+            gossip.remove_peer();
+        }
+        assert_eq!(gossip.network_size, 1.0);
+        assert_eq!(rounds(&gossip), (1, 1, 1));
+    }
+}
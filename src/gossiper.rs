@@ -15,35 +15,49 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use super::gossip::{Gossip, Statistics};
+use super::gossip::{Gossip, GossipSnapshot, Statistics};
 use super::messages::{GossipRpc, Message};
 use ed25519_dalek::{Keypair, PublicKey};
 use error::Error;
+use hasher::{Hasher, Sha3Hasher};
 use id::Id;
 use maidsafe_utilities::serialisation;
-use rand::{self, Rng};
+use rand::{self, Rng, StdRng};
 use serde::ser::Serialize;
 use sha3::Sha3_512;
 use std::fmt::{self, Debug, Formatter};
 
-/// An entity on the network which will gossip messages.
-pub struct Gossiper {
+/// An entity on the network which will gossip messages, identifying them by their hash under `H`.
+pub struct Gossiper<H: Hasher = Sha3Hasher> {
     keys: Keypair,
     peers: Vec<Id>,
-    gossip: Gossip,
+    gossip: Gossip<H>,
+    // The RNG used to choose peers.  If `None`, the thread's RNG is used.
+    rng: Option<StdRng>,
 }
 
-impl Gossiper {
+impl<H: Hasher> Gossiper<H> {
     /// The ID of this `Gossiper`, i.e. its public key.
     pub fn id(&self) -> Id {
         self.keys.public.into()
     }
 
+    /// Returns the protocol handler, e.g. to query the messages held.
+    pub fn gossip(&self) -> &Gossip<H> {
+        &self.gossip
+    }
+
+    /// Returns the protocol handler, e.g. to configure it.  Peers should still be added and removed
+    /// via this `Gossiper` so that the network size matches the known peers.
+    pub fn gossip_mut(&mut self) -> &mut Gossip<H> {
+        &mut self.gossip
+    }
+
     /// Add the ID of another node on the network.  This will fail if `send_new()` has already been
     /// called since this `Gossiper` needs to know about all other nodes in the network before
     /// starting to gossip messages.
     pub fn add_peer(&mut self, peer_id: Id) -> Result<(), Error> {
-        if !self.gossip.messages().is_empty() {
+        if !self.gossip.is_empty() {
             return Err(Error::AlreadyStarted);
         }
         self.peers.push(peer_id);
@@ -51,13 +65,51 @@ impl Gossiper {
         Ok(())
     }
 
-    /// Send a new message starting at this `Gossiper`.
-    pub fn send_new<T: Serialize>(&mut self, message: &T) -> Result<(), Error> {
+    /// Add the IDs of several other nodes on the network.  As with `add_peer()`, this will fail if
+    /// `send_new()` has already been called.
+    pub fn add_peers<I: IntoIterator<Item = Id>>(&mut self, peer_ids: I) -> Result<(), Error> {
+        if !self.gossip.is_empty() {
+            return Err(Error::AlreadyStarted);
+        }
+        self.peers.extend(peer_ids);
+        self.gossip.set_total_peers(self.peers.len() as u64);
+        Ok(())
+    }
+
+    /// Remove the ID of a node which has left the network.  Returns `false` if the peer was not
+    /// known.
+    pub fn remove_peer(&mut self, peer_id: &Id) -> bool {
+        match self.peers.iter().position(|id| id == peer_id) {
+            Some(index) => {
+                let _ = self.peers.remove(index);
+                self.gossip.remove_peer();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Use `rng` rather than the thread's RNG to choose the peer for each round, so that runs of a
+    /// simulation seeded identically make identical choices.
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = Some(rng);
+    }
+
+    /// Send a new message starting at this `Gossiper`.  Returns the hash of the serialised message.
+    /// If the protocol handler is in signed mode (see `Gossip::set_signed()`), the message is
+    /// signed with this gossiper's keys first, and the hash is that of the signed message.
+    pub fn send_new<T: Serialize>(&mut self, message: &T) -> Result<H::Digest, Error> {
         if self.peers.is_empty() {
             return Err(Error::NoPeers);
         }
-        self.gossip.new_message(serialisation::serialise(message)?);
-        Ok(())
+        let msg = serialisation::serialise(message)?;
+        #[cfg(feature = "signed")]
+        {
+            if self.gossip.config().signed {
+                return self.gossip.new_signed_message(msg, &self.keys);
+            }
+        }
+        self.gossip.new_message(msg)
     }
 
     /// Start a new round.  Returns a vector of Push RPCs messages to be sent to the given peer.
@@ -68,8 +120,8 @@ impl Gossiper {
     /// comprising several messages).  However, if we send each Push RPC to a different peer, we'd
     /// receive 100 tranches of Pull RPCs.
     pub fn next_round(&mut self) -> Result<(Id, Vec<Vec<u8>>), Error> {
-        let peer_id = match rand::thread_rng().choose(&self.peers) {
-            Some(id) => *id,
+        let peer_id = match choose_peer(self.rng.as_mut(), &self.peers) {
+            Some(id) => id,
             None => return Err(Error::NoPeers),
         };
         let push_list = self.gossip.next_round();
@@ -78,7 +130,9 @@ impl Gossiper {
         Ok((peer_id, messages))
     }
 
-    /// Handles an incoming message from peer.
+    /// Handles an incoming message from peer.  Every RPC is signed by its sender, so this verifies
+    /// the signature against `peer_id` and drops any message which fails, meaning a peer can't
+    /// forge RPCs, or the counters they carry, on behalf of another.
     pub fn handle_received_message(&mut self, peer_id: &Id, serialised_msg: &[u8]) -> Vec<Vec<u8>> {
         debug!("{:?} handling message from {:?}", self, peer_id);
         let pub_key = if let Ok(pub_key) = PublicKey::from_bytes(&peer_id.0) {
@@ -94,7 +148,13 @@ impl Gossiper {
         };
         // If this RPC is a Push from a peer we've not already heard from in this round, there could
         // be a set of Pull responses to be sent back to that peer.
-        let responses = self.gossip.receive(*peer_id, rpc);
+        let responses = match self.gossip.receive(*peer_id, rpc) {
+            Ok(responses) => responses,
+            Err(error) => {
+                error!("Rejected message from {:?}: {}", peer_id, error);
+                return Vec::new();
+            }
+        };
         self.prepare_to_send(responses)
     }
 
@@ -103,17 +163,43 @@ impl Gossiper {
         self.gossip.messages()
     }
 
+    /// Merges the messages held by `other` into this gossiper's, keeping whichever state is
+    /// furthest through propagation for messages held by both.  See `Gossip::merge()` for when
+    /// this fails.
+    pub fn merge(&mut self, other: &Gossiper<H>) -> Result<(), Error> {
+        self.gossip.merge(&other.gossip)
+    }
+
+    /// Returns a copy of this gossiper's entire gossip state, excluding its keys and peers.
+    pub fn snapshot(&self) -> GossipSnapshot<H::Digest> {
+        self.gossip.snapshot()
+    }
+
+    /// Replaces this gossiper's gossip state with `snapshot`, resuming exactly where the gossiper
+    /// which took it left off.  The snapshot doesn't include the peers, so the same peers should
+    /// be added before calling this.  On failure, as per `Gossip::from_snapshot()`, the current
+    /// state is kept.
+    pub fn restore(&mut self, snapshot: GossipSnapshot<H::Digest>) -> Result<(), Error> {
+        self.gossip = Gossip::from_snapshot(snapshot)?;
+        Ok(())
+    }
+
     /// Returns the statistics of this gossiper.
     pub fn statistics(&self) -> Statistics {
         self.gossip.statistics()
     }
 
-    #[cfg(test)]
-    /// Clear the statistics and gossip's cache.
+    /// Clear the statistics and gossip's cache.  The known peers are kept.
     pub fn clear(&mut self) {
         self.gossip.clear();
     }
 
+    /// Clear the statistics and gossip's cache, and forget all known peers.
+    pub fn reset(&mut self) {
+        self.peers.clear();
+        self.gossip.reset();
+    }
+
     fn prepare_to_send(&mut self, rpcs: Vec<GossipRpc>) -> Vec<Vec<u8>> {
         let mut messages = vec![];
         for rpc in rpcs {
@@ -127,7 +213,7 @@ impl Gossiper {
     }
 }
 
-impl Default for Gossiper {
+impl<H: Hasher> Default for Gossiper<H> {
     fn default() -> Self {
         let mut rng = rand::thread_rng();
         let keys = Keypair::generate::<Sha3_512>(&mut rng);
@@ -135,11 +221,20 @@ impl Default for Gossiper {
             keys,
             peers: vec![],
             gossip: Gossip::new(),
+            rng: None,
         }
     }
 }
 
-impl Debug for Gossiper {
+// Chooses one of `peers` using `rng`, or the thread's RNG if `rng` is `None`.
+pub fn choose_peer(rng: Option<&mut StdRng>, peers: &[Id]) -> Option<Id> {
+    match rng {
+        Some(rng) => rng.choose(peers).cloned(),
+        None => rand::thread_rng().choose(peers).cloned(),
+    }
+}
+
+impl<H: Hasher> Debug for Gossiper<H> {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         write!(formatter, "{:?}", self.id())
     }
@@ -174,7 +269,7 @@ mod tests {
         let mut rng = SeededRng::thread_rng();
         let empty_rpc_len = unwrap!(Message::serialise(
             &GossipRpc::Push {
-                msg: vec![],
+                msg: vec![].into(),
                 counter: 0,
             },
             &gossipers[0].keys,
@@ -343,6 +438,29 @@ mod tests {
         );
     }
 
+    #[cfg(feature = "signed")]
+    #[test]
+    fn signed_mode() {
+        let mut gossipers = create_network(2);
+        for gossiper in &mut gossipers {
+            gossiper.gossip_mut().set_rounds(2, 2, 4);
+            gossiper.gossip_mut().set_signed(true);
+        }
+        let digest = unwrap!(gossipers[0].send_new(&"message"));
+        for _ in 0..4 {
+            let (peer_id, messages) = unwrap!(gossipers[0].next_round());
+            let sender_id = gossipers[0].id();
+            assert_eq!(peer_id, gossipers[1].id());
+            for message in messages {
+                let _ = gossipers[1].handle_received_message(&sender_id, &message);
+            }
+        }
+        assert!(gossipers[1].gossip().has_message(&digest));
+        let (signer, payload) = unwrap!(::signed::open_signed(&gossipers[1].messages()[0]));
+        assert_eq!(Id::from(signer), gossipers[0].id());
+        assert_eq!(payload, unwrap!(serialisation::serialise(&"message")));
+    }
+
     #[test]
     fn one_message() {
         one_message_test(20);
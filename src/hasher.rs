@@ -0,0 +1,79 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha3::{Digest, Sha3_256};
+
+/// The SHA3-256 hash of a message, used to identify it.
+pub type Digest256 = [u8; 32];
+
+/// The hash function used to identify messages.  All nodes in a network must use the same one.
+pub trait Hasher {
+    /// The type of the hash of a message.
+    type Digest: AsRef<[u8]> + Copy + Ord + Serialize + DeserializeOwned;
+
+    /// Returns the hash of `data`.
+    fn hash(data: &[u8]) -> Self::Digest;
+}
+
+/// The default `Hasher`, identifying messages by their SHA3-256 hash.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha3Hasher;
+
+impl Hasher for Sha3Hasher {
+    type Digest = Digest256;
+
+    fn hash(data: &[u8]) -> Digest256 {
+        hash_message(data)
+    }
+}
+
+/// Returns the hash identifying `msg` under the default `Sha3Hasher`, e.g. for passing to
+/// `Gossip::has_message()`.
+pub fn hash_message(msg: &[u8]) -> Digest256 {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&Sha3_256::digest(msg));
+    digest
+}
+
+/// Computes the hash identifying a message under the default `Sha3Hasher` incrementally, e.g. as
+/// its chunks arrive from a streaming transport, so it needn't be buffered twice.  The result is
+/// the same as `hash_message()` of all the chunks concatenated.
+#[derive(Clone, Default)]
+pub struct MessageHasher {
+    state: Sha3_256,
+}
+
+impl MessageHasher {
+    /// Construct a new hasher, yet to be given any of the message.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next chunk of the message to the hasher.
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.state.input(chunk);
+    }
+
+    /// Returns the hash of all the chunks fed so far.
+    pub fn finish(self) -> Digest256 {
+        let mut digest = [0; 32];
+        digest.copy_from_slice(&self.state.result());
+        digest
+    }
+}
@@ -34,6 +34,8 @@
          unused_extern_crates, variant_size_differences, non_camel_case_types)]
 
 extern crate ed25519_dalek;
+#[cfg(feature = "compression")]
+extern crate flate2;
 extern crate futures;
 #[cfg(test)]
 extern crate itertools;
@@ -47,19 +49,41 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate sha3;
+#[cfg(feature = "async")]
+extern crate tokio;
 #[cfg(test)]
 #[macro_use]
 #[cfg(test)]
 extern crate unwrap;
 
+#[cfg(feature = "async")]
+mod async_driver;
+mod compression;
 mod error;
 mod gossip;
 mod gossiper;
+mod hasher;
 mod id;
 mod messages;
 mod message_state;
+mod node;
+pub mod sim;
+#[cfg(feature = "signed")]
+mod signed;
+mod topics;
 
+#[cfg(feature = "async")]
+pub use async_driver::{CancellationToken, RoundDriver};
 pub use error::Error;
-pub use gossip::Statistics;
+pub use gossip::{DEFAULT_MAX_MESSAGE_SIZE, Gossip, GossipBuilder, GossipConfig, GossipEvent,
+                 GossipOp, GossipSnapshot, GossipStats, MAX_EVENTS, MAX_TOTAL_PEERS, MessageRef,
+                 Origin, PushItem, Statistics, StoredMessage};
 pub use gossiper::Gossiper;
+pub use hasher::{Digest256, Hasher, MessageHasher, Sha3Hasher, hash_message};
 pub use id::Id;
+pub use message_state::{MedianRule, MessageState, Phase, RoundParams};
+pub use messages::{GossipRpc, RpcKind, decode_rpcs, encode_rpcs};
+pub use node::{Envelope, MemoryNetwork, MemoryTransport, Node, Transport};
+#[cfg(feature = "signed")]
+pub use signed::open_signed;
+pub use topics::{TopicGossip, TopicId, TopicMut};
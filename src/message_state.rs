@@ -16,11 +16,64 @@
 // relating to use of the SAFE Network Software.
 
 use id::Id;
+use std::{cmp, usize};
 use std::collections::{BTreeMap, BTreeSet};
 use std::collections::btree_map::Entry;
 
+/// The propagation phase of a single message from this node's perspective.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Exponential-growth phase (state B).
+    B,
+    /// Quadratic-shrinking phase (state C).
+    C,
+    /// Propagation complete (state D).
+    D,
+}
+
+/// The rule by which peers' counters for a message in state B advance our own at the end of a
+/// round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MedianRule {
+    /// Our counter is incremented by one if peers' counters at least as high as ours outweigh
+    /// those lower.  This is the rule given in the paper, and the default.
+    Majority,
+    /// Our counter is raised to the (lower, weighted) median of peers' counters if that's higher.
+    /// This lets a node lagging well behind its peers catch up in a single round.
+    Median,
+}
+
+impl Default for MedianRule {
+    fn default() -> Self {
+        MedianRule::Majority
+    }
+}
+
+/// The parameters by which `MessageState::next_round_with_rule()` advances a message at the end of
+/// a round.
+#[derive(Clone, Copy, Debug)]
+pub struct RoundParams<'a> {
+    /// The counter at which a message moves from state B to state C.
+    pub counter_max: u8,
+    /// The maximum number of rounds a message spends in state C.
+    pub max_c_rounds: u8,
+    /// The maximum total number of rounds a message spends in states B and C.
+    pub max_rounds: u8,
+    /// The peers which contacted us this round.  Any which didn't send a copy of the message are
+    /// taken to be in state A for it.
+    pub peers_in_this_round: &'a BTreeSet<Id>,
+    /// The weight of each peer's counter in the median rule's tally.  Peers without an entry have a
+    /// weight of `1`.
+    pub peer_weights: &'a BTreeMap<Id, u32>,
+    /// The rule by which peers' counters advance ours.
+    pub rule: MedianRule,
+    /// The maximum number of peers' counters tallied, as per `receive()`.  Peers taken to be in
+    /// state A only fill any room left by those which sent a copy.
+    pub max_hits: usize,
+}
+
 /// This represents the state of a single message from this node's perspective.
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum MessageState {
     /// Exponential-growth phase.
     B {
@@ -34,7 +87,9 @@ pub enum MessageState {
         /// The map of <peer, counter>s which have sent us this message during this round.
         peer_counters: BTreeMap<Id, u8>,
     },
-    /// Quadratic-shrinking phase.
+    /// Quadratic-shrinking phase.  Peers' counters have already done their job by moving the
+    /// message here, so they're no longer recorded; the message is simply pushed for a fixed number
+    /// of rounds.
     C {
         /// The number of rounds performed by the node while the message was in state B.
         rounds_in_state_b: u8,
@@ -59,6 +114,9 @@ impl MessageState {
     /// Construct a new `MessageState` where we've received the message from a peer.  If that peer
     /// is in state B (`counter < counter_max`) we start in state B with `our_counter` set to `1`.
     /// If the peer is in state C, we start in state C too.
+    ///
+    /// The peer's counter only selects the phase: we always start at round 0, so however large
+    /// the counter, a newly received message is still pushed for the full number of rounds.
     pub fn new_from_peer(counter: u8, counter_max: u8) -> Self {
         if counter < counter_max {
             return MessageState::B {
@@ -73,16 +131,73 @@ impl MessageState {
         }
     }
 
-    /// Receive a copy of this message from `peer_id` with `counter`.
-    pub fn receive(&mut self, peer_id: Id, counter: u8) {
+    /// Receive a copy of this message from `peer_id` with `counter`.  At most `max_hits` distinct
+    /// peers' counters are recorded per round; copies from any further peers are ignored, in which
+    /// case `false` is returned.
+    pub fn receive(&mut self, peer_id: Id, counter: u8, max_hits: usize) -> bool {
         if let MessageState::B { ref mut peer_counters, .. } = *self {
+            if peer_counters.len() >= max_hits && !peer_counters.contains_key(&peer_id) {
+                debug!(
+                    "Already received {} counters this round - ignoring one from {:?}",
+                    max_hits,
+                    peer_id
+                );
+                return false;
+            }
             if peer_counters.insert(peer_id, counter).is_some() {
                 debug!("Received the same message more than once this round from a given peer");
             }
         }
+        true
     }
 
-    /// Increment `round` value, consuming `self` and returning the new state.
+    /// Merge another node's view of this message into ours.  If the states are in different
+    /// phases, the one further through propagation wins.  Otherwise we take the maximum of each
+    /// round and counter, and of each peer's counter.
+    pub fn merge(&mut self, other: &MessageState) {
+        match (self, other) {
+            (
+                &mut MessageState::B {
+                    ref mut round,
+                    ref mut our_counter,
+                    ref mut peer_counters,
+                },
+                &MessageState::B {
+                    round: other_round,
+                    our_counter: other_counter,
+                    peer_counters: ref other_peer_counters,
+                },
+            ) => {
+                *round = cmp::max(*round, other_round);
+                *our_counter = cmp::max(*our_counter, other_counter);
+                for (peer_id, other_peer_counter) in other_peer_counters {
+                    let peer_counter = peer_counters.entry(*peer_id).or_insert(0);
+                    *peer_counter = cmp::max(*peer_counter, *other_peer_counter);
+                }
+            }
+            (
+                &mut MessageState::C {
+                    ref mut rounds_in_state_b,
+                    ref mut round,
+                },
+                &MessageState::C {
+                    rounds_in_state_b: other_rounds_in_state_b,
+                    round: other_round,
+                },
+            ) => {
+                *rounds_in_state_b = cmp::max(*rounds_in_state_b, other_rounds_in_state_b);
+                *round = cmp::max(*round, other_round);
+            }
+            (this, other) => {
+                if other.phase() > this.phase() {
+                    *this = other.clone();
+                }
+            }
+        }
+    }
+
+    /// Increment `round` value, consuming `self` and returning the new state.  All rounds and
+    /// counters saturate at `u8::MAX` rather than overflowing.
     pub fn next_round(
         self,
         counter_max: u8,
@@ -90,22 +205,51 @@ impl MessageState {
         max_rounds: u8,
         peers_in_this_round: &BTreeSet<Id>,
     ) -> MessageState {
+        let params = RoundParams {
+            counter_max,
+            max_c_rounds,
+            max_rounds,
+            peers_in_this_round,
+            peer_weights: &BTreeMap::new(),
+            rule: MedianRule::Majority,
+            max_hits: usize::MAX,
+        };
+        self.next_round_with_rule(&params).0
+    }
+
+    /// As per `next_round()`, but taking the full set of `params`, including the weights of peers'
+    /// counters and the rule by which they advance ours.  The second value returned is whether our
+    /// counter was changed.
+    pub fn next_round_with_rule(self, params: &RoundParams) -> (MessageState, bool) {
+        let RoundParams {
+            counter_max,
+            max_c_rounds,
+            max_rounds,
+            peers_in_this_round,
+            peer_weights,
+            rule,
+            max_hits,
+        } = *params;
         match self {
             MessageState::B {
                 mut round,
                 mut our_counter,
                 mut peer_counters,
             } => {
-                round += 1;
+                round = round.saturating_add(1);
                 // If we've hit the maximum permitted number of rounds, transition to state D
                 if round >= max_rounds {
-                    return MessageState::D;
+                    return (MessageState::D, false);
                 }
 
                 // For any `peers_in_this_round` which aren't accounted for in `peer_counters`, add
                 // a counter of `0` for them to indicate they're in state A (i.e. they didn't have
-                // the message).
+                // the message).  As with `receive()`, stop once `max_hits` counters are recorded:
+                // the peers beyond that may have been ignored while sending the message.
                 for peer in peers_in_this_round {
+                    if peer_counters.len() >= max_hits {
+                        break;
+                    }
                     if let Entry::Vacant(entry) = peer_counters.entry(*peer) {
                         let _ = entry.insert(0);
                     }
@@ -113,60 +257,101 @@ impl MessageState {
 
                 // Apply the median rule, but if any peer's counter >= `counter_max` (i.e. that peer
                 // is in state C), transition to state C.
-                let mut less = 0;
-                let mut greater_or_equal = 0;
-                for peer_counter in peer_counters.values() {
+                let mut less = 0u64;
+                let mut greater_or_equal = 0u64;
+                for (peer, peer_counter) in &peer_counters {
+                    let weight = peer_weights.get(peer).map_or(1, |weight| u64::from(*weight));
                     if *peer_counter < our_counter {
-                        less += 1;
+                        less += weight;
                     } else if *peer_counter >= counter_max {
-                        return MessageState::C {
+                        let state = MessageState::C {
                             rounds_in_state_b: round,
                             round: 0,
                         };
+                        return (state, false);
                     } else {
-                        greater_or_equal += 1;
+                        greater_or_equal += weight;
                     }
                 }
-                if greater_or_equal > less {
-                    our_counter += 1;
-                }
+                let bumped = match rule {
+                    MedianRule::Majority => {
+                        let bumped = greater_or_equal > less;
+                        if bumped {
+                            our_counter = our_counter.saturating_add(1);
+                        }
+                        bumped
+                    }
+                    MedianRule::Median => {
+                        let median = weighted_median(&peer_counters, peer_weights);
+                        let bumped = median > our_counter;
+                        if bumped {
+                            our_counter = median;
+                        }
+                        bumped
+                    }
+                };
 
                 // If our counter has reached `counter_max`, transition to state C, otherwise remain
                 // in state B.
                 if our_counter >= counter_max {
-                    return MessageState::C {
+                    let state = MessageState::C {
                         rounds_in_state_b: round,
                         round: 0,
                     };
+                    return (state, bumped);
                 }
-                MessageState::B {
+                let state = MessageState::B {
                     round,
                     our_counter,
                     peer_counters: BTreeMap::new(),
-                }
+                };
+                (state, bumped)
             }
             MessageState::C {
                 rounds_in_state_b,
                 mut round,
             } => {
-                round += 1;
+                round = round.saturating_add(1);
                 // If we've hit the maximum permitted number of rounds, transition to state D
-                if round + rounds_in_state_b >= max_rounds {
-                    return MessageState::D;
+                if round.saturating_add(rounds_in_state_b) >= max_rounds {
+                    return (MessageState::D, false);
                 }
 
                 // If we've hit the maximum rounds for remaining in state C, transition to state D.
                 if round >= max_c_rounds {
-                    return MessageState::D;
+                    return (MessageState::D, false);
                 }
 
                 // Otherwise remain in state C.
-                MessageState::C {
+                let state = MessageState::C {
                     rounds_in_state_b,
                     round,
-                }
+                };
+                (state, false)
             }
-            MessageState::D => MessageState::D,
+            MessageState::D => (MessageState::D, false),
+        }
+    }
+
+    /// The phase this message is currently in.
+    pub fn phase(&self) -> Phase {
+        match *self {
+            MessageState::B { .. } => Phase::B,
+            MessageState::C { .. } => Phase::C,
+            MessageState::D => Phase::D,
+        }
+    }
+
+    /// The total number of rounds this message has spent in states B and C, or `None` if in
+    /// state D.
+    pub fn rounds(&self) -> Option<u8> {
+        match *self {
+            MessageState::B { round, .. } => Some(round),
+            MessageState::C {
+                rounds_in_state_b,
+                round,
+            } => Some(rounds_in_state_b.saturating_add(round)),
+            MessageState::D => None,
         }
     }
 
@@ -180,3 +365,150 @@ impl MessageState {
         }
     }
 }
+
+// Returns the lower median of `peer_counters`, with each weighted by `peer_weights` as per
+// `RoundParams`, or `0` if there are none.
+fn weighted_median(peer_counters: &BTreeMap<Id, u8>, peer_weights: &BTreeMap<Id, u32>) -> u8 {
+    let mut weighted: Vec<(u8, u64)> = peer_counters
+        .iter()
+        .map(|(peer, counter)| {
+            let weight = peer_weights.get(peer).map_or(1, |weight| u64::from(*weight));
+            (*counter, weight)
+        })
+        .collect();
+    weighted.sort();
+    let total: u64 = weighted.iter().map(|&(_, weight)| weight).sum();
+    let mut cumulative = 0;
+    for (counter, weight) in weighted {
+        cumulative += weight;
+        if cumulative * 2 >= total {
+            return counter;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saturating_counters() {
+        let peers = BTreeSet::new();
+        let mut peer_counters = BTreeMap::new();
+        let _ = peer_counters.insert(Id([0; 32]), u8::max_value());
+        let state = MessageState::B {
+            round: u8::max_value(),
+            our_counter: u8::max_value(),
+            peer_counters,
+        };
+        match state.next_round(u8::max_value(), u8::max_value(), u8::max_value(), &peers) {
+            MessageState::D => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+
+        let state = MessageState::C {
+            rounds_in_state_b: u8::max_value(),
+            round: u8::max_value(),
+        };
+        match state.next_round(u8::max_value(), u8::max_value(), u8::max_value(), &peers) {
+            MessageState::D => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+    }
+
+    #[test]
+    fn weighted_median() {
+        let peers = BTreeSet::new();
+        let mut peer_counters = BTreeMap::new();
+        for index in 0..3 {
+            let _ = peer_counters.insert(Id([index; 32]), if index == 0 { 2 } else { 0 });
+        }
+        let state = MessageState::B {
+            round: 0,
+            our_counter: 1,
+            peer_counters,
+        };
+
+        // Unweighted, the two peers in state A outvote the one ahead of us.
+        let mut params = RoundParams {
+            counter_max: 5,
+            max_c_rounds: 2,
+            max_rounds: 10,
+            peers_in_this_round: &peers,
+            peer_weights: &BTreeMap::new(),
+            rule: MedianRule::Majority,
+            max_hits: usize::MAX,
+        };
+        match state.clone().next_round_with_rule(&params) {
+            (MessageState::B { our_counter: 1, .. }, false) => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+        let mut peer_weights = BTreeMap::new();
+        let _ = peer_weights.insert(Id([0; 32]), 3);
+        params.peer_weights = &peer_weights;
+        match state.clone().next_round_with_rule(&params) {
+            (MessageState::B { our_counter: 2, .. }, true) => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+        // As with equal counts, equal total weights don't increment our counter.
+        let mut equal_weights = BTreeMap::new();
+        let _ = equal_weights.insert(Id([0; 32]), 2);
+        params.peer_weights = &equal_weights;
+        match state.next_round_with_rule(&params) {
+            (MessageState::B { our_counter: 1, .. }, false) => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+    }
+
+    #[test]
+    fn median_rules() {
+        let peers = BTreeSet::new();
+        let no_weights = BTreeMap::new();
+        let mut params = RoundParams {
+            counter_max: 6,
+            max_c_rounds: 2,
+            max_rounds: 10,
+            peers_in_this_round: &peers,
+            peer_weights: &no_weights,
+            rule: MedianRule::Majority,
+            max_hits: usize::MAX,
+        };
+        let mut peer_counters = BTreeMap::new();
+        for index in 0..5 {
+            let _ = peer_counters.insert(Id([index; 32]), if index < 3 { 4 } else { 0 });
+        }
+        let state = MessageState::B {
+            round: 0,
+            our_counter: 1,
+            peer_counters,
+        };
+
+        // Most peers are well ahead of us: the majority rule only takes one step towards them,
+        // whereas the median rule catches straight up.
+        match state.clone().next_round_with_rule(&params) {
+            (MessageState::B { our_counter: 2, .. }, true) => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+        params.rule = MedianRule::Median;
+        match state.next_round_with_rule(&params) {
+            (MessageState::B { our_counter: 4, .. }, true) => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+
+        // A median below our counter leaves it unchanged.
+        let mut peer_counters = BTreeMap::new();
+        for index in 0..3 {
+            let _ = peer_counters.insert(Id([index; 32]), index);
+        }
+        let state = MessageState::B {
+            round: 0,
+            our_counter: 2,
+            peer_counters,
+        };
+        match state.next_round_with_rule(&params) {
+            (MessageState::B { our_counter: 2, .. }, false) => (),
+            state => panic!("Unexpected state: {:?}", state),
+        }
+    }
+}
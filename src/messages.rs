@@ -20,6 +20,14 @@ use error::Error;
 use maidsafe_utilities::serialisation;
 #[cfg(not(test))]
 use sha3::Sha3_512;
+use std::sync::Arc;
+use std::u32;
+
+// The tags identifying each kind of RPC in the compact encoding.
+const PUSH_TAG: u8 = 0;
+const PULL_TAG: u8 = 1;
+// The size of the tag, counter and length prefix preceding each message in the compact encoding.
+const HEADER_LEN: usize = 6;
 
 /// Messages sent via a direct connection, wrapper of gossip protocol rpcs.
 #[derive(Serialize, Debug, Deserialize)]
@@ -54,11 +62,146 @@ impl Message {
     }
 }
 
+/// The kind of a gossip RPC, for use where its message is passed separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RpcKind {
+    /// A Push RPC.
+    Push,
+    /// A Pull RPC.
+    Pull,
+}
+
 /// Gossip rpcs
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum GossipRpc {
     /// Sent from Node A to Node B to push a message and its counter.
-    Push { msg: Vec<u8>, counter: u8 },
+    Push {
+        /// The message.
+        #[serde(with = "shared_bytes")]
+        msg: Arc<[u8]>,
+        /// Node A's counter for the message.
+        counter: u8,
+    },
     /// Sent from Node B to Node A as a reaction to receiving a push message from A.
-    Pull { msg: Vec<u8>, counter: u8 },
+    Pull {
+        /// The message.
+        #[serde(with = "shared_bytes")]
+        msg: Arc<[u8]>,
+        /// Node B's counter for the message.
+        counter: u8,
+    },
+}
+
+/// Encodes `rpcs` in a compact form for sending over a stream, to be decoded via `decode_rpcs()`.
+/// Each RPC is encoded as a byte for its kind (`0` for Push, `1` for Pull), a byte for its
+/// counter, then its message prefixed by the message's length as a big-endian `u32`.  Returns
+/// `TooLarge` if any message is too long for its length to fit.
+pub fn encode_rpcs(rpcs: &[GossipRpc]) -> Result<Vec<u8>, Error> {
+    let len = rpcs.iter().map(|rpc| HEADER_LEN + parts(rpc).2.len()).sum();
+    let mut bytes = Vec::with_capacity(len);
+    for rpc in rpcs {
+        let (tag, counter, msg) = parts(rpc);
+        if msg.len() > u32::MAX as usize {
+            return Err(Error::TooLarge);
+        }
+        let msg_len = msg.len() as u32;
+        bytes.push(tag);
+        bytes.push(counter);
+        bytes.extend_from_slice(&[
+            (msg_len >> 24) as u8,
+            (msg_len >> 16) as u8,
+            (msg_len >> 8) as u8,
+            msg_len as u8,
+        ]);
+        bytes.extend_from_slice(msg);
+    }
+    Ok(bytes)
+}
+
+/// Decodes RPCs encoded via `encode_rpcs()`.  Returns `Malformed` if `bytes` is truncated or
+/// contains an unknown kind of RPC.
+pub fn decode_rpcs(mut bytes: &[u8]) -> Result<Vec<GossipRpc>, Error> {
+    let mut rpcs = vec![];
+    while !bytes.is_empty() {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::Malformed);
+        }
+        let msg_len = bytes[2..HEADER_LEN]
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        if bytes.len() - HEADER_LEN < msg_len {
+            return Err(Error::Malformed);
+        }
+        let msg = bytes[HEADER_LEN..HEADER_LEN + msg_len].to_vec().into();
+        let counter = bytes[1];
+        rpcs.push(match bytes[0] {
+            PUSH_TAG => GossipRpc::Push { msg, counter },
+            PULL_TAG => GossipRpc::Pull { msg, counter },
+            _ => return Err(Error::Malformed),
+        });
+        bytes = &bytes[HEADER_LEN + msg_len..];
+    }
+    Ok(rpcs)
+}
+
+// Returns the tag, counter and message of `rpc`.
+fn parts(rpc: &GossipRpc) -> (u8, u8, &[u8]) {
+    match *rpc {
+        GossipRpc::Push { ref msg, counter } => (PUSH_TAG, counter, msg),
+        GossipRpc::Pull { ref msg, counter } => (PULL_TAG, counter, msg),
+    }
+}
+
+// (De)serialises a shared message as its bytes, in the same form as a `Vec<u8>`, without needing
+// serde's "rc" feature.
+pub mod shared_bytes {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::sync::Arc;
+
+    pub fn serialize<S: Serializer>(msg: &Arc<[u8]>, serializer: S) -> Result<S::Ok, S::Error> {
+        (**msg).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Arc<[u8]>, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(Arc::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_encoding() {
+        let push = GossipRpc::Push {
+            msg: b"message".to_vec().into(),
+            counter: 3,
+        };
+        let pull = GossipRpc::Pull {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let lists = vec![vec![], vec![push.clone()], vec![push.clone(), pull, push]];
+        for rpcs in lists {
+            let encoded = unwrap!(encode_rpcs(&rpcs));
+            assert_eq!(unwrap!(decode_rpcs(&encoded)), rpcs);
+        }
+
+        let encoded = unwrap!(encode_rpcs(&[GossipRpc::Pull {
+            msg: vec![9; 300].into(),
+            counter: 1,
+        }]));
+        assert_eq!(&encoded[..HEADER_LEN], &[PULL_TAG, 1, 0, 0, 1, 44]);
+        assert_eq!(encoded.len(), HEADER_LEN + 300);
+
+        // Truncated headers or messages, and unknown tags, are rejected.
+        let mut unknown_tag = encoded.clone();
+        unknown_tag[0] = 2;
+        for malformed in &[&encoded[..3], &encoded[..encoded.len() - 1], &unknown_tag[..]] {
+            match decode_rpcs(malformed) {
+                Err(Error::Malformed) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+    }
 }
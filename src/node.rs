@@ -0,0 +1,313 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+#[cfg(feature = "signed")]
+use ed25519_dalek::Keypair;
+use error::Error;
+use gossip::Gossip;
+use gossiper::choose_peer;
+use hasher::{Hasher, Sha3Hasher};
+use id::Id;
+use messages::GossipRpc;
+use rand::StdRng;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Delivers RPCs to peers on behalf of a `Node`.
+pub trait Transport {
+    /// Send `rpcs` to `peer`, to be passed in order to `Node::receive()` there.
+    fn send(&self, peer: &Id, rpcs: &[GossipRpc]) -> Result<(), Error>;
+}
+
+/// A node on the network, wiring a `Gossip` protocol handler to a `Transport`.  The handler itself
+/// never touches the network, so it can still be driven directly via `gossip_mut()`.
+pub struct Node<T: Transport, H: Hasher = Sha3Hasher> {
+    id: Id,
+    gossip: Gossip<H>,
+    transport: T,
+    // The RNG used to choose peers.  If `None`, the thread's RNG is used.
+    rng: Option<StdRng>,
+}
+
+impl<T: Transport, H: Hasher> Node<T, H> {
+    /// Construct a new node with no peers, sending via `transport`.
+    pub fn new(id: Id, transport: T) -> Self {
+        Node {
+            id,
+            gossip: Gossip::new(),
+            transport,
+            rng: None,
+        }
+    }
+
+    /// The ID of this node.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// Returns the protocol handler.
+    pub fn gossip(&self) -> &Gossip<H> {
+        &self.gossip
+    }
+
+    /// Returns the protocol handler, e.g. to configure it.
+    pub fn gossip_mut(&mut self) -> &mut Gossip<H> {
+        &mut self.gossip
+    }
+
+    /// Returns the transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Use `rng` rather than the thread's RNG to choose the peer for each round, so that runs of a
+    /// simulation seeded identically make identical choices.
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = Some(rng);
+    }
+
+    /// Add another node on the network.  Returns `false` if it was already known.
+    pub fn add_peer(&mut self, peer_id: Id) -> bool {
+        self.gossip.add_peer_id(peer_id)
+    }
+
+    /// Remove a node which has left the network.  Returns `false` if it wasn't known.
+    pub fn remove_peer(&mut self, peer_id: &Id) -> bool {
+        self.gossip.remove_peer_id(peer_id)
+    }
+
+    /// Start gossiping a new message from this node.  Returns the hash of the message.
+    pub fn new_message(&mut self, msg: Vec<u8>) -> Result<H::Digest, Error> {
+        if self.gossip.peer_ids().next().is_none() {
+            return Err(Error::NoPeers);
+        }
+        self.gossip.new_message(msg)
+    }
+
+    /// Start gossiping `payload` from this node, signed with `keys`, as required if the protocol
+    /// handler is in signed mode.  See `Gossip::new_signed_message()`.
+    #[cfg(feature = "signed")]
+    pub fn new_signed_message(
+        &mut self,
+        payload: Vec<u8>,
+        keys: &Keypair,
+    ) -> Result<H::Digest, Error> {
+        if self.gossip.peer_ids().next().is_none() {
+            return Err(Error::NoPeers);
+        }
+        self.gossip.new_signed_message(payload, keys)
+    }
+
+    /// Trigger the end of this round, sending the Push RPCs for the new round to a single random
+    /// peer.  Returns the ID of that peer.
+    pub fn next_round(&mut self) -> Result<Id, Error> {
+        let peer_ids: Vec<Id> = self.gossip.peer_ids().cloned().collect();
+        let peer_id = match choose_peer(self.rng.as_mut(), &peer_ids) {
+            Some(id) => id,
+            None => return Err(Error::NoPeers),
+        };
+        let push_list = self.gossip.next_round();
+        debug!("{:?} Sending Push messages to {:?}", self.id, peer_id);
+        self.transport.send(&peer_id, &push_list)?;
+        Ok(peer_id)
+    }
+
+    /// We've received `rpcs` from `peer_id`.  Any Pull RPCs due in response are sent back to
+    /// `peer_id`.  As per `Gossip::receive_batch()`, if any RPC is invalid, none of them are
+    /// handled.
+    pub fn receive(&mut self, peer_id: Id, rpcs: Vec<GossipRpc>) -> Result<(), Error> {
+        let responses = self.gossip.receive_batch(peer_id, rpcs)?;
+        if responses.is_empty() {
+            return Ok(());
+        }
+        self.transport.send(&peer_id, &responses)
+    }
+}
+
+/// RPCs sent via a `MemoryTransport`, queued for delivery.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    /// The ID of the sending node.
+    pub sender: Id,
+    /// The ID of the node to which the RPCs should be delivered.
+    pub recipient: Id,
+    /// The RPCs, in the order sent.
+    pub rpcs: Vec<GossipRpc>,
+}
+
+/// An in-memory network, queueing all RPCs sent via its transports until taken for delivery.
+/// Clones share the same queue.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    queue: Arc<Mutex<VecDeque<Envelope>>>,
+}
+
+impl MemoryNetwork {
+    /// Construct a new network with nothing queued.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a transport for the node `id`, queueing its RPCs on this network.
+    pub fn transport(&self, id: Id) -> MemoryTransport {
+        MemoryTransport {
+            id,
+            network: self.clone(),
+        }
+    }
+
+    /// Removes and returns the oldest queued envelope, or `None` if nothing is queued.  Fails with
+    /// `Error::Poisoned` if a panic occurred while the queue was locked.
+    pub fn take(&self) -> Result<Option<Envelope>, Error> {
+        Ok(self.queue.lock().map_err(|_| Error::Poisoned)?.pop_front())
+    }
+}
+
+/// A `Transport` which queues RPCs on a `MemoryNetwork`, primarily for tests and simulations.
+#[derive(Clone)]
+pub struct MemoryTransport {
+    id: Id,
+    network: MemoryNetwork,
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, peer: &Id, rpcs: &[GossipRpc]) -> Result<(), Error> {
+        self.network
+            .queue
+            .lock()
+            .map_err(|_| Error::Poisoned)?
+            .push_back(Envelope {
+                sender: self.id,
+                recipient: *peer,
+                rpcs: rpcs.to_vec(),
+            });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::PUBLIC_KEY_LENGTH;
+    use rand::SeedableRng;
+    #[cfg(feature = "signed")]
+    use sha3::Sha3_512;
+
+    // Returns `count` nodes on `network`, each with all the others as peers.
+    fn connected_nodes(network: &MemoryNetwork, count: u8) -> Vec<Node<MemoryTransport>> {
+        let ids: Vec<Id> = (0..count).map(|index| Id([index; PUBLIC_KEY_LENGTH])).collect();
+        ids.iter()
+            .map(|id| {
+                let mut node = Node::new(*id, network.transport(*id));
+                for peer_id in ids.iter().filter(|peer_id| *peer_id != id) {
+                    assert!(node.add_peer(*peer_id));
+                }
+                node
+            })
+            .collect()
+    }
+
+    // Delivers everything queued on `network`, including any responses sent in turn.
+    fn deliver_all(network: &MemoryNetwork, nodes: &mut [Node<MemoryTransport>]) {
+        while let Some(envelope) = unwrap!(network.take()) {
+            let node = unwrap!(nodes.iter_mut().find(|node| node.id() == envelope.recipient));
+            unwrap!(node.receive(envelope.sender, envelope.rpcs));
+        }
+    }
+
+    #[test]
+    fn two_nodes() {
+        let network = MemoryNetwork::new();
+        let mut nodes = connected_nodes(&network, 2);
+        for node in &mut nodes {
+            // The thresholds derived for a network of two are too low for a message to be pushed.
+            node.gossip_mut().set_rounds(2, 2, 4);
+        }
+
+        let digest = unwrap!(nodes[0].new_message(b"message".to_vec()));
+        assert!(!nodes[1].gossip().has_message(&digest));
+        for _ in 0..4 {
+            for node in &mut nodes {
+                let peer_id = unwrap!(node.next_round());
+                assert_ne!(peer_id, node.id());
+            }
+            deliver_all(&network, &mut nodes);
+        }
+        assert!(nodes.iter().all(|node| node.gossip().has_message(&digest)));
+        assert!(unwrap!(network.take()).is_none());
+    }
+
+    #[cfg(feature = "signed")]
+    #[test]
+    fn signed_mode() {
+        let network = MemoryNetwork::new();
+        let mut nodes = connected_nodes(&network, 2);
+        for node in &mut nodes {
+            node.gossip_mut().set_rounds(2, 2, 4);
+            node.gossip_mut().set_signed(true);
+        }
+        let keys = Keypair::generate::<Sha3_512>(&mut StdRng::from_seed(&[0][..]));
+        match nodes[0].new_message(b"unsigned".to_vec()) {
+            Err(Error::SigFailure) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let digest = unwrap!(nodes[0].new_signed_message(b"message".to_vec(), &keys));
+        for _ in 0..4 {
+            for node in &mut nodes {
+                let _ = unwrap!(node.next_round());
+            }
+            deliver_all(&network, &mut nodes);
+        }
+        assert!(nodes.iter().all(|node| node.gossip().has_message(&digest)));
+
+        // An unsigned message from a peer is rejected along with the rest of its batch.
+        let rpcs = vec![
+            GossipRpc::Push {
+                msg: b"unsigned".to_vec().into(),
+                counter: 1,
+            },
+        ];
+        let sender_id = nodes[0].id();
+        match nodes[1].receive(sender_id, rpcs) {
+            Err(Error::SigFailure) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(nodes[1].gossip().message_count(), 1);
+    }
+
+    #[test]
+    fn seeded_runs_are_identical() {
+        let run = || {
+            let network = MemoryNetwork::new();
+            let mut nodes = connected_nodes(&network, 5);
+            for node in &mut nodes {
+                node.set_rng(StdRng::from_seed(&[1, 2, 3][..]));
+            }
+            let _ = unwrap!(nodes[0].new_message(b"message".to_vec()));
+            let mut history = vec![];
+            for _ in 0..10 {
+                for node in &mut nodes {
+                    history.push(unwrap!(node.next_round()));
+                }
+                deliver_all(&network, &mut nodes);
+            }
+            let spread = nodes.iter().filter(|node| !node.gossip().is_empty()).count();
+            (history, spread)
+        };
+        assert_eq!(run(), run());
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+use error::Error;
+use gossip::Gossip;
+use hasher::Hasher;
+use maidsafe_utilities::serialisation;
+use sha3::Sha3_512;
+
+// A message as gossiped in signed mode, so that its hash covers both the payload and its signer.
+#[derive(Serialize, Deserialize)]
+struct SignedMessage {
+    signer: PublicKey,
+    payload: Vec<u8>,
+    signature: Signature,
+}
+
+impl<H: Hasher> Gossip<H> {
+    /// Start gossiping `payload` from this node, signed with `keys` so that peers in signed mode
+    /// (see `set_signed()`) accept it.  Returns the hash of the signed message, which covers the
+    /// signer as well as the payload.
+    pub fn new_signed_message(
+        &mut self,
+        payload: Vec<u8>,
+        keys: &Keypair,
+    ) -> Result<H::Digest, Error> {
+        let signature = keys.sign::<Sha3_512>(&payload);
+        let signed = SignedMessage {
+            signer: keys.public,
+            payload,
+            signature,
+        };
+        self.new_message(serialisation::serialise(&signed)?)
+    }
+}
+
+/// Verifies a message gossiped in signed mode, returning its signer and payload, or `SigFailure`
+/// if it isn't validly signed.
+pub fn open_signed(msg: &[u8]) -> Result<(PublicKey, Vec<u8>), Error> {
+    let signed: SignedMessage = serialisation::deserialise(msg).map_err(|_| Error::SigFailure)?;
+    if !signed.signer.verify::<Sha3_512>(&signed.payload, &signed.signature) {
+        return Err(Error::SigFailure);
+    }
+    Ok((signed.signer, signed.payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gossip::GossipBuilder;
+    use id::Id;
+    use messages::GossipRpc;
+    use rand::{SeedableRng, StdRng};
+
+    #[test]
+    fn signed_messages() {
+        let mut rng = StdRng::from_seed(&[0][..]);
+        let keys = Keypair::generate::<Sha3_512>(&mut rng);
+        let mut sender: Gossip = GossipBuilder::new().total_peers(20).signed(true).build();
+        let mut receiver: Gossip = GossipBuilder::new().total_peers(20).signed(true).build();
+        assert!(receiver.config().signed);
+        let digest = unwrap!(sender.new_signed_message(b"message".to_vec(), &keys));
+        for rpc in sender.next_round() {
+            let _ = unwrap!(receiver.receive(Id([0; 32]), rpc));
+        }
+        assert!(receiver.has_message(&digest));
+        let (signer, payload) = unwrap!(open_signed(&receiver.messages()[0]));
+        assert_eq!(signer, keys.public);
+        assert_eq!(payload, b"message".to_vec());
+
+        // Neither unsigned nor tampered messages are accepted, whether new or from peers.
+        let tampered = SignedMessage {
+            signer: keys.public,
+            payload: b"forged".to_vec(),
+            signature: keys.sign::<Sha3_512>(b"message"),
+        };
+        for msg in vec![b"unsigned".to_vec(), unwrap!(serialisation::serialise(&tampered))] {
+            match sender.new_message(msg.clone()) {
+                Err(Error::SigFailure) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+            let rpc = GossipRpc::Push {
+                msg: msg.into(),
+                counter: 1,
+            };
+            match receiver.receive(Id([1; 32]), rpc.clone()) {
+                Err(Error::SigFailure) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+            match receiver.receive_batch(Id([1; 32]), vec![rpc]) {
+                Err(Error::SigFailure) => (),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+        assert_eq!(receiver.message_count(), 1);
+        receiver.verify_invariants();
+
+        // Empty RPCs carry no message, so are still accepted.
+        let rpc = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let _ = unwrap!(receiver.receive(Id([2; 32]), rpc));
+
+        // Outside signed mode, unsigned messages are accepted as usual.
+        receiver.set_signed(false);
+        let _ = unwrap!(receiver.new_message(b"unsigned".to_vec()));
+        assert_eq!(receiver.message_count(), 2);
+    }
+}
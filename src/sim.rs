@@ -0,0 +1,75 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Helpers for simulating networks of `Gossip` instances without any transport.
+
+use error::Error;
+use gossip::Gossip;
+use hasher::Hasher;
+use id::Id;
+
+/// Performs `src`'s part in a round: `src` starts a new round and pushes to `dst`, which receives
+/// the Pushes and replies with its Pulls, which `src` then receives.  The IDs identify the nodes to
+/// each other.
+///
+/// In a simulation, every node should call this once per round as `src`, choosing a random `dst`.
+/// Nodes only advance their own rounds here, so the order in which they do so within a round
+/// doesn't matter.
+pub fn gossip_round<H: Hasher>(
+    src_id: Id,
+    src: &mut Gossip<H>,
+    dst_id: Id,
+    dst: &mut Gossip<H>,
+) -> Result<(), Error> {
+    for push in src.next_round() {
+        for pull in dst.receive(src_id, push)? {
+            let _ = src.receive(dst_id, pull)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::PUBLIC_KEY_LENGTH;
+
+    #[test]
+    fn two_nodes_converge() {
+        let (a_id, b_id) = (Id([0; PUBLIC_KEY_LENGTH]), Id([1; PUBLIC_KEY_LENGTH]));
+        // Use the thresholds of a larger network, since those for two nodes end gossiping before
+        // the first push.
+        let mut a: Gossip = Gossip::with_peers(20);
+        let mut b: Gossip = Gossip::with_peers(20);
+        let from_a = unwrap!(a.new_message(b"from a".to_vec()));
+        let from_b = unwrap!(b.new_message(b"from b".to_vec()));
+
+        let mut rounds = 0;
+        while !(a.all_terminated() && b.all_terminated()) {
+            assert!(rounds < 10, "Failed to terminate.");
+            unwrap!(gossip_round(a_id, &mut a, b_id, &mut b));
+            unwrap!(gossip_round(b_id, &mut b, a_id, &mut a));
+            a.verify_invariants();
+            b.verify_invariants();
+            rounds += 1;
+        }
+        for node in &[a, b] {
+            assert!(node.has_message(&from_a));
+            assert!(node.has_message(&from_b));
+        }
+    }
+}
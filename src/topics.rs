@@ -0,0 +1,305 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use error::Error;
+use gossip::Gossip;
+use hasher::{Hasher, Sha3Hasher};
+use id::Id;
+use messages::GossipRpc;
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+
+/// Identifies an independent stream of gossiped messages.
+pub type TopicId = u32;
+
+/// Gossip protocol handler for several independent topics sharing the same network.  Each topic
+/// has its own messages, counters and rounds, but the network size is common to all of them.
+///
+/// Topics are only created by this node starting a message in one, or by a peer sending a
+/// non-empty message in one, in which case the number held can be capped via `set_max_topics()`.
+pub struct TopicGossip<H: Hasher = Sha3Hasher> {
+    topics: BTreeMap<TopicId, Gossip<H>>,
+    // The number of peers in the network, excluding this node.
+    total_peers: u64,
+    // The maximum number of topics which peers can cause to be held.
+    max_topics: Option<usize>,
+}
+
+impl<H: Hasher> TopicGossip<H> {
+    /// Construct a new `TopicGossip` with no topics, for a network comprising just this node.
+    pub fn new() -> Self {
+        TopicGossip {
+            topics: BTreeMap::new(),
+            total_peers: 0,
+            max_topics: None,
+        }
+    }
+
+    /// Increase the network size by one for all topics.
+    pub fn add_peer(&mut self) {
+        let total_peers = self.total_peers.saturating_add(1);
+        self.set_total_peers(total_peers);
+    }
+
+    /// Decrease the network size by one for all topics, never dropping below this node alone.
+    pub fn remove_peer(&mut self) {
+        let total_peers = self.total_peers.saturating_sub(1);
+        self.set_total_peers(total_peers);
+    }
+
+    /// Set the number of peers in the network (excluding this node) for all topics.
+    pub fn set_total_peers(&mut self, total_peers: u64) {
+        self.total_peers = total_peers;
+        for gossip in self.topics.values_mut() {
+            gossip.set_total_peers(total_peers);
+        }
+    }
+
+    /// Set the maximum number of topics held, beyond which messages from peers in new topics are
+    /// rejected, or `None` (the default) for no limit.  This node can still start messages in new
+    /// topics regardless.
+    pub fn set_max_topics(&mut self, max_topics: Option<usize>) {
+        self.max_topics = max_topics;
+    }
+
+    /// Returns the IDs of all topics currently held, in ascending order.
+    pub fn topics(&self) -> Vec<TopicId> {
+        self.topics.keys().cloned().collect()
+    }
+
+    /// Returns the handler for the given topic, or `None` if it isn't held.
+    pub fn topic(&self, topic: TopicId) -> Option<&Gossip<H>> {
+        self.topics.get(&topic)
+    }
+
+    /// Returns the handler for the given topic, or `None` if it isn't held.  As the network size is
+    /// common to all topics, any peers added or removed via the handler are discarded when it's
+    /// dropped; use `TopicGossip`'s own methods to change them.
+    pub fn topic_mut<'a>(&'a mut self, topic: TopicId) -> Option<TopicMut<'a, H>> {
+        let total_peers = self.total_peers;
+        self.topics
+            .get_mut(&topic)
+            .map(|gossip| TopicMut { gossip, total_peers })
+    }
+
+    /// Removes the given topic along with all of its messages.  Returns `false` if it wasn't held.
+    pub fn remove_topic(&mut self, topic: TopicId) -> bool {
+        self.topics.remove(&topic).is_some()
+    }
+
+    /// Start gossiping a new message from this node within the given topic, creating the topic if
+    /// it isn't already held.  Returns the hash of the message.
+    pub fn new_message(&mut self, topic: TopicId, msg: Vec<u8>) -> Result<H::Digest, Error> {
+        self.topic_entry(topic).new_message(msg)
+    }
+
+    /// Trigger the end of this round for the given topic.  Returns a list of Push RPCs to be sent
+    /// to a single random peer during this new round, or an empty list if the topic isn't held.
+    pub fn next_round(&mut self, topic: TopicId) -> Vec<GossipRpc> {
+        self.topics
+            .get_mut(&topic)
+            .map_or_else(Vec::new, |gossip| gossip.next_round())
+    }
+
+    /// We've received `rpc` from `peer_id` within the given topic.  Returns the list of Pull RPCs
+    /// which should be sent back to `peer_id` within the same topic, as per `Gossip::receive()`.
+    ///
+    /// If the topic isn't held, it's only created if `rpc` carries a message and fewer than the
+    /// maximum number of topics are held, otherwise failing with `Error::TooManyTopics`.  An empty
+    /// Push in such a topic is just answered with an empty Pull, as this node holds nothing there.
+    pub fn receive(
+        &mut self,
+        topic: TopicId,
+        peer_id: Id,
+        rpc: GossipRpc,
+    ) -> Result<Vec<GossipRpc>, Error> {
+        if let Some(gossip) = self.topics.get_mut(&topic) {
+            return gossip.receive(peer_id, rpc);
+        }
+        let (is_push, msg_is_empty, counter) = match rpc {
+            GossipRpc::Push { ref msg, counter } => (true, msg.is_empty(), counter),
+            GossipRpc::Pull { ref msg, counter } => (false, msg.is_empty(), counter),
+        };
+        if msg_is_empty {
+            if counter != 0 {
+                return Err(Error::Empty);
+            }
+            if !is_push {
+                return Ok(vec![]);
+            }
+            return Ok(vec![GossipRpc::Pull {
+                msg: Vec::new().into(),
+                counter: 0,
+            }]);
+        }
+        if self.max_topics.map_or(false, |max_topics| self.topics.len() >= max_topics) {
+            debug!("Ignoring a message in new topic {} as too many are held", topic);
+            return Err(Error::TooManyTopics);
+        }
+        // Only keep the new topic if the message is accepted.
+        let mut gossip = new_topic(self.total_peers);
+        let responses = gossip.receive(peer_id, rpc)?;
+        let _ = self.topics.insert(topic, gossip);
+        Ok(responses)
+    }
+
+    fn topic_entry(&mut self, topic: TopicId) -> &mut Gossip<H> {
+        let total_peers = self.total_peers;
+        self.topics
+            .entry(topic)
+            .or_insert_with(|| new_topic(total_peers))
+    }
+}
+
+// Returns the handler for a new topic in a network of `total_peers` peers.
+fn new_topic<H: Hasher>(total_peers: u64) -> Gossip<H> {
+    let mut gossip = Gossip::new();
+    gossip.set_total_peers(total_peers);
+    gossip
+}
+
+/// Mutable access to a single topic's handler, as returned by `TopicGossip::topic_mut()`.  When
+/// dropped, the topic's network size is reset to that shared by all topics.
+pub struct TopicMut<'a, H: Hasher + 'a> {
+    gossip: &'a mut Gossip<H>,
+    total_peers: u64,
+}
+
+impl<'a, H: Hasher> Deref for TopicMut<'a, H> {
+    type Target = Gossip<H>;
+
+    fn deref(&self) -> &Gossip<H> {
+        self.gossip
+    }
+}
+
+impl<'a, H: Hasher> DerefMut for TopicMut<'a, H> {
+    fn deref_mut(&mut self) -> &mut Gossip<H> {
+        self.gossip
+    }
+}
+
+impl<'a, H: Hasher> Drop for TopicMut<'a, H> {
+    fn drop(&mut self) {
+        let peer_ids: Vec<Id> = self.gossip.peer_ids().cloned().collect();
+        for peer_id in &peer_ids {
+            let _ = self.gossip.remove_peer_id(peer_id);
+        }
+        self.gossip.set_total_peers(self.total_peers);
+    }
+}
+
+impl<H: Hasher> Default for TopicGossip<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::PUBLIC_KEY_LENGTH;
+    use hasher::hash_message;
+    use message_state::Phase;
+
+    #[test]
+    fn topics_are_independent() {
+        let mut topics: TopicGossip = TopicGossip::new();
+        topics.set_total_peers(20);
+        let digest = unwrap!(topics.new_message(1, b"message".to_vec()));
+        assert_eq!(topics.topics(), vec![1]);
+
+        // A late topic picks up the shared network size.
+        let rpc = GossipRpc::Push {
+            msg: b"message".to_vec().into(),
+            counter: 2,
+        };
+        let responses = unwrap!(topics.receive(7, Id([0; PUBLIC_KEY_LENGTH]), rpc));
+        assert_eq!(topics.topics(), vec![1, 7]);
+        let first = unwrap!(topics.topic(1));
+        let second = unwrap!(topics.topic(7));
+        assert_eq!(first.max_rounds(), second.max_rounds());
+
+        // The same message is held separately, in different states, by each topic.
+        assert_eq!(responses.len(), 1);
+        assert_eq!(first.message_phase(&digest), Some(Phase::B));
+        assert_eq!(second.message_phase(&hash_message(b"message")), Some(Phase::C));
+
+        assert_eq!(topics.next_round(1).len(), 1);
+        assert_eq!(unwrap!(topics.topic(1)).current_round(), 1);
+        assert_eq!(unwrap!(topics.topic(7)).current_round(), 0);
+        assert!(topics.next_round(3).is_empty());
+
+        assert!(topics.remove_topic(1));
+        assert!(!topics.remove_topic(1));
+        assert_eq!(topics.topics(), vec![7]);
+    }
+
+    #[test]
+    fn topics_created_by_peers() {
+        let mut topics: TopicGossip = TopicGossip::new();
+        topics.set_total_peers(20);
+        topics.set_max_topics(Some(1));
+        let peer_id = Id([0; PUBLIC_KEY_LENGTH]);
+        let rpc = |msg: &[u8]| GossipRpc::Push {
+            msg: msg.to_vec().into(),
+            counter: 1,
+        };
+
+        // Empty RPCs in unknown topics don't create them, an empty Push just getting an empty Pull.
+        let empty_push = GossipRpc::Push {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        let responses = unwrap!(topics.receive(1, peer_id, empty_push));
+        match responses[..] {
+            [GossipRpc::Pull { ref msg, counter: 0 }] => assert!(msg.is_empty()),
+            _ => panic!("Unexpected responses {:?}", responses),
+        }
+        let empty_pull = GossipRpc::Pull {
+            msg: Vec::new().into(),
+            counter: 0,
+        };
+        assert!(unwrap!(topics.receive(1, peer_id, empty_pull)).is_empty());
+        assert!(topics.receive(1, peer_id, rpc(b"")).is_err());
+        assert!(topics.topics().is_empty());
+
+        // Nor do rejected messages.
+        assert!(topics.receive(1, peer_id, rpc(&vec![0; 5 * 1024 * 1024])).is_err());
+        assert!(topics.topics().is_empty());
+
+        // Beyond the cap, peers can't create topics, though this node still can.
+        let _ = unwrap!(topics.receive(1, peer_id, rpc(b"message")));
+        match topics.receive(2, peer_id, rpc(b"message")) {
+            Err(Error::TooManyTopics) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let _ = unwrap!(topics.new_message(2, b"message".to_vec()));
+        assert_eq!(topics.topics(), vec![1, 2]);
+
+        // The network size can't be changed for a single topic.
+        let max_rounds = unwrap!(topics.topic(1)).max_rounds();
+        {
+            let mut topic = unwrap!(topics.topic_mut(1));
+            topic.set_total_peers(1_000_000);
+            assert!(topic.add_peer_id(peer_id));
+        }
+        let topic = unwrap!(topics.topic(1));
+        assert_eq!(topic.max_rounds(), max_rounds);
+        assert_eq!(topic.peer_ids().count(), 0);
+    }
+}